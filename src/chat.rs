@@ -1,14 +1,56 @@
-use std::{collections::HashMap, sync::Arc};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use reqwest::{Client, Error};
-use serde::Deserialize;
+use reqwest::{Client, Response};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
 use serde_json::json;
+use tokio::io::AsyncWriteExt;
 use ijson::{IString, IValue as Value};
 
+use super::error::{Error, ErrorCode};
+
+#[derive(Clone)]
 pub struct MatrixClient {
     client: Client,
-    homeserver: String,
-    access_code: String,
+    session: Arc<RwLock<Option<Session>>>,
+
+    // filter JSON to the id the server gave it, so each filter is only uploaded once
+    filters: Arc<RwLock<HashMap<String, Arc<String>>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub base_url: String,
+    pub user_id: Arc<String>,
+    pub access_token: String,
+    pub device_id: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct LoginResponse {
+    user_id: Arc<String>,
+    access_token: String,
+    device_id: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct WellKnownHomeserver {
+    base_url: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct WellKnown {
+    #[serde(rename = "m.homeserver")]
+    homeserver: WellKnownHomeserver,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Versions {
+    #[allow(dead_code)]
+    versions: Vec<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -53,6 +95,8 @@ pub struct RoomEvent {
     pub sender: Arc<String>,
     pub origin_server_ts: u64,
     pub unsigned: UnsignedData,
+    pub redacts: Option<Arc<String>>,
+    pub state_key: Option<Arc<String>>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -62,9 +106,179 @@ pub struct Timeline {
     pub prev_batch: String,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct Typing {
+    pub user_ids: Vec<Arc<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Receipt {
+    pub ts: Option<u64>,
+}
+
+// event id -> receipt type -> user id
+pub type Receipts = HashMap<Arc<String>, HashMap<String, HashMap<Arc<String>, Receipt>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReceiptType {
+    Read,
+    ReadPrivate,
+}
+
+impl ReceiptType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReceiptType::Read => "m.read",
+            ReceiptType::ReadPrivate => "m.read.private",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum EphemeralEvent {
+    #[serde(rename = "m.typing")]
+    Typing { content: Typing },
+
+    #[serde(rename = "m.receipt")]
+    Receipt { content: Receipts },
+
+    #[serde(other)]
+    Other,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Ephemeral {
-    pub events: Vec<Value>,
+    pub events: Vec<EphemeralEvent>,
+}
+
+fn default_moderation_level() -> i64 {
+    50
+}
+
+// missing keys fall back to the defaults from the spec, not to zero
+#[derive(Deserialize, Debug, Clone)]
+pub struct PowerLevels {
+    #[serde(default = "default_moderation_level")]
+    pub ban: i64,
+    #[serde(default = "default_moderation_level")]
+    pub kick: i64,
+    #[serde(default)]
+    pub invite: i64,
+    #[serde(default = "default_moderation_level")]
+    pub redact: i64,
+    #[serde(default)]
+    pub events_default: i64,
+    #[serde(default = "default_moderation_level")]
+    pub state_default: i64,
+    #[serde(default)]
+    pub users_default: i64,
+    #[serde(default)]
+    pub events: HashMap<String, i64>,
+    #[serde(default)]
+    pub users: HashMap<String, i64>,
+}
+
+impl Default for PowerLevels {
+    fn default() -> PowerLevels {
+        PowerLevels {
+            ban: 50,
+            kick: 50,
+            invite: 0,
+            redact: 50,
+            events_default: 0,
+            state_default: 50,
+            users_default: 0,
+            events: HashMap::new(),
+            users: HashMap::new(),
+        }
+    }
+}
+
+impl PowerLevels {
+    pub fn user_level(&self, user: &str) -> i64 {
+        self.users.get(user).copied().unwrap_or(self.users_default)
+    }
+
+    pub fn event_level(&self, event_type: &str) -> i64 {
+        self.events.get(event_type).copied().unwrap_or(self.events_default)
+    }
+
+    pub fn state_level(&self, event_type: &str) -> i64 {
+        self.events.get(event_type).copied().unwrap_or(self.state_default)
+    }
+
+    pub fn can_invite(&self, user: &str) -> bool {
+        self.user_level(user) >= self.invite
+    }
+
+    // kicking and banning also need a higher level than whoever is on the receiving end
+    pub fn can_kick(&self, user: &str, target: &str) -> bool {
+        let level = self.user_level(user);
+        level >= self.kick && level > self.user_level(target)
+    }
+
+    pub fn can_ban(&self, user: &str, target: &str) -> bool {
+        let level = self.user_level(user);
+        level >= self.ban && level > self.user_level(target)
+    }
+
+    // an unban is the banned user's membership going to leave, which the server treats as a kick too
+    pub fn can_unban(&self, user: &str, target: &str) -> bool {
+        self.can_ban(user, target) && self.user_level(user) >= self.kick
+    }
+}
+
+// other clients put all sorts into account data, and one bad event mustn't fail the whole sync
+fn lenient<'de, D: Deserializer<'de>, T: DeserializeOwned + Default>(deserializer: D) -> Result<T, D::Error> {
+    let value = Value::deserialize(deserializer)?;
+    Ok(ijson::from_value(&value).unwrap_or_default())
+}
+
+// drops just the entries that don't parse rather than the whole map
+fn lenient_map<'de, D: Deserializer<'de>, T: DeserializeOwned>(deserializer: D) -> Result<HashMap<Arc<String>, T>, D::Error> {
+    let map: HashMap<Arc<String>, Value> = lenient(deserializer)?;
+    Ok(map
+        .into_iter()
+        .filter_map(|(k, v)| Some((k, ijson::from_value(&v).ok()?)))
+        .collect())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TagInfo {
+    #[serde(default, deserialize_with = "lenient")]
+    pub order: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Tags {
+    #[serde(default, deserialize_with = "lenient_map")]
+    pub tags: HashMap<Arc<String>, TagInfo>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum AccountDataEvent {
+    #[serde(rename = "m.tag")]
+    Tag {
+        #[serde(default, deserialize_with = "lenient")]
+        content: Tags,
+    },
+
+    // user id -> rooms
+    #[serde(rename = "m.direct")]
+    Direct {
+        #[serde(default, deserialize_with = "lenient_map")]
+        content: HashMap<Arc<String>, Vec<Arc<String>>>,
+    },
+
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AccountData {
+    pub events: Vec<AccountDataEvent>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -73,22 +287,177 @@ pub struct UnreadNotificationCounts {
     pub notification_count: i64,
 }
 
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RoomSummary {
+    #[serde(rename = "m.heroes")]
+    pub heroes: Option<Vec<Arc<String>>>,
+
+    #[serde(rename = "m.joined_member_count")]
+    pub joined_member_count: Option<u64>,
+
+    #[serde(rename = "m.invited_member_count")]
+    pub invited_member_count: Option<u64>,
+}
+
+// everything the spec's room name calculation needs, kept up to date from sync
+#[derive(Debug, Clone, Default)]
+pub struct RoomNameState {
+    name: Option<String>,
+    canonical_alias: Option<String>,
+    heroes: Vec<Arc<String>>,
+    display_names: HashMap<Arc<String>, String>,
+    joined_member_count: u64,
+    invited_member_count: u64,
+}
+
+impl RoomNameState {
+    // the summary only includes fields that changed since the last sync
+    pub fn apply_summary(&mut self, summary: &RoomSummary) -> bool {
+        let mut changed = false;
+        if let Some(heroes) = &summary.heroes {
+            changed |= *heroes != self.heroes;
+            self.heroes = heroes.clone();
+        }
+        if let Some(count) = summary.joined_member_count {
+            changed |= count != self.joined_member_count;
+            self.joined_member_count = count;
+        }
+        if let Some(count) = summary.invited_member_count {
+            changed |= count != self.invited_member_count;
+            self.invited_member_count = count;
+        }
+        changed
+    }
+
+    pub fn apply_state(&mut self, event_type: &str, state_key: &Arc<String>, content: &Value) -> bool {
+        let string = |key: &str| {
+            content
+                .get(key)
+                .and_then(Value::as_string)
+                .map(IString::as_str)
+                .filter(|v| !v.is_empty())
+                .map(String::from)
+        };
+
+        match event_type {
+            "m.room.name" => {
+                let name = string("name");
+                let changed = name != self.name;
+                self.name = name;
+                changed
+            }
+
+            "m.room.canonical_alias" => {
+                let alias = string("alias");
+                let changed = alias != self.canonical_alias;
+                self.canonical_alias = alias;
+                changed
+            }
+
+            "m.room.member" => {
+                let changed = match string("displayname") {
+                    Some(name) => self.display_names.insert(state_key.clone(), name.clone()) != Some(name),
+                    None => self.display_names.remove(state_key).is_some(),
+                };
+                changed && self.heroes.contains(state_key)
+            }
+
+            _ => false,
+        }
+    }
+
+    pub fn member_name<'a>(&'a self, user_id: &'a Arc<String>) -> &'a str {
+        self.display_names.get(user_id).map(String::as_str).unwrap_or(user_id.as_str())
+    }
+
+    pub fn display_name(&self, user_id: &str) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        if let Some(alias) = &self.canonical_alias {
+            return alias.clone();
+        }
+
+        let heroes: Vec<&str> = self
+            .heroes
+            .iter()
+            .filter(|v| v.as_str() != user_id)
+            .map(|v| self.display_names.get(v).map(String::as_str).unwrap_or(v.as_str()))
+            .collect();
+        let others = (self.joined_member_count + self.invited_member_count).saturating_sub(1);
+
+        if others == 0 {
+            if heroes.is_empty() {
+                String::from("Empty room")
+            } else {
+                format!("Empty room (was {})", join_names(&heroes, 0))
+            }
+        } else {
+            join_names(&heroes, others.saturating_sub(heroes.len() as u64))
+        }
+    }
+}
+
+fn join_names(names: &[&str], remaining: u64) -> String {
+    let mut parts: Vec<String> = names.iter().map(|v| String::from(*v)).collect();
+    match remaining {
+        0 => (),
+        1 => parts.push(String::from("1 other")),
+        n => parts.push(format!("{} others", n)),
+    }
+
+    match parts.split_last() {
+        None => String::new(),
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct JoinedRoom {
-    pub name: Option<Arc<String>>,
-    pub summary: HashMap<String, Value>,
+    #[serde(default)]
+    pub summary: RoomSummary,
     pub state: State,
     pub timeline: Timeline,
     pub ephemeral: Ephemeral,
-    pub account_data: Value,
+
+    #[serde(default)]
+    pub account_data: AccountData,
     pub unread_notifications: UnreadNotificationCounts,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct StrippedStateEvent {
+    pub content: Value,
+
+    #[serde(rename = "type")]
+    pub type_: Arc<String>,
+
+    pub state_key: Arc<String>,
+    pub sender: Arc<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct InviteState {
+    pub events: Vec<StrippedStateEvent>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct InvitedRoom {
+    #[serde(default)]
+    pub invite_state: InviteState,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LeftRoom {
+    pub state: Option<State>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct SyncRooms {
     pub join: Option<HashMap<Arc<String>, JoinedRoom>>,
-    pub invite: Option<HashMap<Arc<String>, Value>>,
-    pub leave: Option<HashMap<Arc<String>, Value>>,
+    pub invite: Option<HashMap<Arc<String>, InvitedRoom>>,
+    pub leave: Option<HashMap<Arc<String>, LeftRoom>>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -96,7 +465,7 @@ pub struct SyncState {
     pub next_batch: Arc<String>,
     pub rooms: Option<SyncRooms>,
     pub presence: Option<Value>,
-    pub account_data: Option<Value>,
+    pub account_data: Option<AccountData>,
     pub to_device: Option<Value>,
     pub device_lists: Option<Value>,
     pub device_one_time_keys_count: Option<Value>,
@@ -109,6 +478,231 @@ pub struct Content {
     pub content: Vec<u8>,
 }
 
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct EventFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    senders: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_senders: Option<Vec<String>>,
+}
+
+impl EventFilter {
+    pub fn new() -> EventFilter {
+        EventFilter::default()
+    }
+
+    pub fn limit(mut self, limit: u64) -> EventFilter {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn types(mut self, types: &[&str]) -> EventFilter {
+        self.types = Some(types.iter().map(|v| String::from(*v)).collect());
+        self
+    }
+
+    pub fn not_types(mut self, types: &[&str]) -> EventFilter {
+        self.not_types = Some(types.iter().map(|v| String::from(*v)).collect());
+        self
+    }
+
+    pub fn senders(mut self, senders: &[&str]) -> EventFilter {
+        self.senders = Some(senders.iter().map(|v| String::from(*v)).collect());
+        self
+    }
+
+    pub fn not_senders(mut self, senders: &[&str]) -> EventFilter {
+        self.not_senders = Some(senders.iter().map(|v| String::from(*v)).collect());
+        self
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RoomEventFilter {
+    #[serde(flatten)]
+    events: EventFilter,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contains_url: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lazy_load_members: Option<bool>,
+}
+
+impl RoomEventFilter {
+    pub fn new() -> RoomEventFilter {
+        RoomEventFilter::default()
+    }
+
+    pub fn limit(mut self, limit: u64) -> RoomEventFilter {
+        self.events = self.events.limit(limit);
+        self
+    }
+
+    pub fn types(mut self, types: &[&str]) -> RoomEventFilter {
+        self.events = self.events.types(types);
+        self
+    }
+
+    pub fn not_types(mut self, types: &[&str]) -> RoomEventFilter {
+        self.events = self.events.not_types(types);
+        self
+    }
+
+    pub fn senders(mut self, senders: &[&str]) -> RoomEventFilter {
+        self.events = self.events.senders(senders);
+        self
+    }
+
+    pub fn not_senders(mut self, senders: &[&str]) -> RoomEventFilter {
+        self.events = self.events.not_senders(senders);
+        self
+    }
+
+    pub fn contains_url(mut self, contains_url: bool) -> RoomEventFilter {
+        self.contains_url = Some(contains_url);
+        self
+    }
+
+    pub fn lazy_load_members(mut self, lazy_load_members: bool) -> RoomEventFilter {
+        self.lazy_load_members = Some(lazy_load_members);
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RoomFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<RoomEventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeline: Option<RoomEventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ephemeral: Option<RoomEventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account_data: Option<RoomEventFilter>,
+}
+
+impl RoomFilter {
+    pub fn new() -> RoomFilter {
+        RoomFilter::default()
+    }
+
+    pub fn state(mut self, filter: RoomEventFilter) -> RoomFilter {
+        self.state = Some(filter);
+        self
+    }
+
+    pub fn timeline(mut self, filter: RoomEventFilter) -> RoomFilter {
+        self.timeline = Some(filter);
+        self
+    }
+
+    pub fn ephemeral(mut self, filter: RoomEventFilter) -> RoomFilter {
+        self.ephemeral = Some(filter);
+        self
+    }
+
+    pub fn account_data(mut self, filter: RoomEventFilter) -> RoomFilter {
+        self.account_data = Some(filter);
+        self
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Filter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence: Option<EventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account_data: Option<EventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<RoomFilter>,
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    pub fn presence(mut self, filter: EventFilter) -> Filter {
+        self.presence = Some(filter);
+        self
+    }
+
+    pub fn account_data(mut self, filter: EventFilter) -> Filter {
+        self.account_data = Some(filter);
+        self
+    }
+
+    pub fn room(mut self, filter: RoomFilter) -> Filter {
+        self.room = Some(filter);
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[derive(Deserialize)]
+struct FilterId {
+    filter_id: Arc<String>,
+}
+
+#[derive(Deserialize)]
+struct CreatedRoom {
+    room_id: Arc<String>,
+}
+
+#[derive(Deserialize)]
+struct JoinedRoomId {
+    room_id: Arc<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpaceChild {
+    pub state_key: Arc<String>,
+    pub content: Value,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpaceRoom {
+    pub room_id: Arc<String>,
+    pub name: Option<Arc<String>>,
+    pub canonical_alias: Option<Arc<String>>,
+    pub topic: Option<Arc<String>>,
+    pub room_type: Option<Arc<String>>,
+    pub num_joined_members: u64,
+
+    #[serde(default)]
+    pub children_state: Vec<SpaceChild>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpaceHierarchy {
+    pub rooms: Vec<SpaceRoom>,
+    pub next_batch: Option<Arc<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub filename: String,
+    pub mimetype: String,
+    pub size: u64,
+}
+
+#[derive(Deserialize)]
+struct UploadResponse {
+    content_uri: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct RoomMessages {
     pub start: Arc<String>,
@@ -117,62 +711,476 @@ pub struct RoomMessages {
     pub state: Option<Vec<StateEvent>>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoomPreset {
+    Private,
+    Public,
+    TrustedPrivate,
+}
+
+impl RoomPreset {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoomPreset::Private => "private_chat",
+            RoomPreset::Public => "public_chat",
+            RoomPreset::TrustedPrivate => "trusted_private_chat",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateRoom {
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    // just the localpart, the server adds the # and its own name
+    pub alias: Option<String>,
+    pub preset: Option<RoomPreset>,
+    pub invite: Vec<String>,
+    pub is_direct: bool,
+    pub encrypted: bool,
+    pub room_type: Option<String>,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum RoomDirection {
     Forwards,
     Backwards
 }
 
+async fn check_status(response: Response) -> Result<Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(response)
+    } else {
+        let body = response.text().await?;
+        Err(Error::from_status(status.as_u16(), &body))
+    }
+}
+
+async fn text(response: Response) -> Result<String, Error> {
+    Ok(check_status(response).await?.text().await?)
+}
+
+fn parse<T: DeserializeOwned>(text: &str) -> Result<T, Error> {
+    Ok(serde_json::from_str::<Value>(text).and_then(|v| ijson::from_value(&v))?)
+}
+
+pub fn parse_sync(text: &str) -> Result<SyncState, Error> {
+    parse(text)
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplyTo {
+    pub event_id: Arc<String>,
+    pub sender: Arc<String>,
+    pub body: Arc<String>,
+    pub formatted: Option<Arc<String>>,
+}
+
+impl ReplyTo {
+    fn fallback_body(&self, content: &str) -> String {
+        let mut body = String::new();
+        for (i, line) in strip_reply_fallback(&self.body).lines().enumerate() {
+            if i == 0 {
+                body.push_str(&format!("> <{}> {}\n", self.sender, line));
+            } else {
+                body.push_str(&format!("> {}\n", line));
+            }
+        }
+
+        body.push('\n');
+        body.push_str(content);
+        body
+    }
+
+    fn fallback_html(&self, room: &str, formatted: &str) -> String {
+        let parent = match &self.formatted {
+            Some(v) => String::from(strip_reply_fallback_html(v)),
+            None => escape_html(strip_reply_fallback(&self.body)).replace('\n', "<br />"),
+        };
+
+        format!(
+            "<mx-reply><blockquote><a href=\"https://matrix.to/#/{}/{}\">In reply to</a> <a href=\"https://matrix.to/#/{}\">{}</a><br />{}</blockquote></mx-reply>{}",
+            room, self.event_id, self.sender, self.sender, parent, formatted,
+        )
+    }
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_path_segment(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => escaped.push(b as char),
+            _ => escaped.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    escaped
+}
+
+pub fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+
+    let mut rest = body;
+    while rest.starts_with('>') {
+        rest = match rest.find('\n') {
+            Some(i) => &rest[i + 1..],
+            None => "",
+        };
+    }
+
+    rest.strip_prefix('\n').unwrap_or(rest)
+}
+
+pub fn strip_reply_fallback_html(html: &str) -> &str {
+    match html.find("</mx-reply>") {
+        Some(i) if html.starts_with("<mx-reply>") => &html[i + "</mx-reply>".len()..],
+        _ => html,
+    }
+}
+
+pub fn guess_mimetype(filename: &str) -> &'static str {
+    let extension = match filename.rsplit_once('.') {
+        Some((_, v)) => v.to_lowercase(),
+        None => return "application/octet-stream",
+    };
+
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "svg" => "image/svg+xml",
+        "txt" => "text/plain",
+        "md" => "text/markdown",
+        "html" | "htm" => "text/html",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "json" => "application/json",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+const SEND_ATTEMPTS: u32 = 5;
+
+// typing, read markers and tags share one queue and are no use late, so a slow server can't
+// hold everything behind them up for long
+const QUICK_TIMEOUT: Duration = Duration::from_secs(10);
+
+static TRANSACTION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub fn new_transaction_id() -> Arc<String> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis())
+        .unwrap_or(0);
+    let count = TRANSACTION_COUNTER.fetch_add(1, Ordering::Relaxed);
+    Arc::new(format!("uwutalk.{}.{}", time, count))
+}
+
+impl Default for MatrixClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MatrixClient {
-    pub fn new(homeserver: &str, access_code: &str) -> MatrixClient {
+    pub fn new() -> MatrixClient {
+        MatrixClient {
+            client: Client::new(),
+            session: Arc::new(RwLock::new(None)),
+            filters: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_session(session: Session) -> MatrixClient {
         MatrixClient {
             client: Client::new(),
-            homeserver: String::from(homeserver),
-            access_code: String::from(access_code),
+            session: Arc::new(RwLock::new(Some(session))),
+            filters: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn session(&self) -> Option<Session> {
+        self.session.read().unwrap().clone()
+    }
+
+    fn base_url(&self) -> String {
+        match &*self.session.read().unwrap() {
+            Some(session) => session.base_url.clone(),
+            None => String::new(),
+        }
+    }
+
+    fn access_token(&self) -> String {
+        match &*self.session.read().unwrap() {
+            Some(session) => session.access_token.clone(),
+            None => String::new(),
         }
     }
 
+    fn user_id(&self) -> Arc<String> {
+        match &*self.session.read().unwrap() {
+            Some(session) => session.user_id.clone(),
+            None => Arc::new(String::new()),
+        }
+    }
+
+    pub async fn resolve_homeserver(&self, server: &str) -> Result<String, Error> {
+        let server = server.trim().trim_end_matches('/');
+        let base_url = if server.starts_with("https://") || server.starts_with("http://") {
+            String::from(server)
+        } else {
+            let well_known = self
+                .client
+                .get(format!("https://{}/.well-known/matrix/client", server))
+                .send()
+                .await
+                .ok()
+                .filter(|v| v.status() != reqwest::StatusCode::NOT_FOUND);
+
+            // no .well-known at all means the server name is the homeserver, but one that's
+            // there and broken has to be fixed rather than guessed around
+            let delegated = match well_known {
+                Some(v) => {
+                    let response = text(v).await?;
+                    Some(parse::<WellKnown>(&response)?.homeserver.base_url)
+                }
+                None => None,
+            };
+
+            match delegated {
+                Some(v) => String::from(v.trim_end_matches('/')),
+                None => format!("https://{}", server),
+            }
+        };
+
+        let response = self
+            .client
+            .get(format!("{}/_matrix/client/versions", base_url))
+            .send()
+            .await?;
+        let versions = text(response).await?;
+        parse::<Versions>(&versions)?;
+
+        Ok(base_url)
+    }
+
+    pub async fn login(&self, server: &str, user: &str, password: &str) -> Result<Session, Error> {
+        let base_url = self.resolve_homeserver(server).await?;
+        let body = json!({
+            "type": "m.login.password",
+            "identifier": {
+                "type": "m.id.user",
+                "user": user,
+            },
+            "password": password,
+            "initial_device_display_name": "uwutalk",
+        })
+        .to_string();
+
+        let response = self
+            .client
+            .post(format!(
+                "{}/_matrix/client/r0/login",
+                base_url
+            ))
+            .body(body)
+            .send()
+            .await?;
+        let response = text(response).await?;
+        let response: LoginResponse = parse(&response)?;
+        let session = Session {
+            base_url,
+            user_id: response.user_id,
+            access_token: response.access_token,
+            device_id: response.device_id,
+        };
+
+        *self.session.write().unwrap() = Some(session.clone());
+        self.filters.write().unwrap().clear();
+        Ok(session)
+    }
+
+    pub async fn logout(&self) -> Result<(), Error> {
+        let result = self
+            .client
+            .post(format!(
+                "{}/_matrix/client/r0/logout",
+                self.base_url()
+            ))
+            .bearer_auth(self.access_token())
+            .send()
+            .await;
+
+        *self.session.write().unwrap() = None;
+        self.filters.write().unwrap().clear();
+        text(result?).await.map(|_| ())
+    }
+
+    async fn put_transaction(&self, url: String, body: String) -> Result<Event, Error> {
+        let mut delay = Duration::from_millis(500);
+        let mut attempt = 1;
+        loop {
+            let response = self
+                .client
+                .put(&url)
+                .body(body.clone())
+                .bearer_auth(self.access_token())
+                .send()
+                .await;
+            let result = match response {
+                Ok(v) => match text(v).await {
+                    Ok(v) => parse(&v),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e.into()),
+            };
+
+            // the transaction id makes it safe to send the same request again
+            match result {
+                Err(Error::Transport(_)) if attempt < SEND_ATTEMPTS => (),
+
+                Err(Error::Status { errcode: ErrorCode::LimitExceeded, retry_after_ms, .. }) if attempt < SEND_ATTEMPTS => {
+                    if let Some(ms) = retry_after_ms {
+                        delay = Duration::from_millis(ms);
+                    }
+                }
+
+                result => return result,
+            }
+
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    pub async fn send_event(
+        &self,
+        room: &str,
+        event_type: &str,
+        txn_id: &str,
+        body: String,
+    ) -> Result<Event, Error> {
+        let url = format!(
+            "{}/_matrix/client/r0/rooms/{}/send/{}/{}",
+            self.base_url(), room, event_type, txn_id
+        );
+        self.put_transaction(url, body).await
+    }
+
+    pub async fn send_reaction(
+        &self,
+        room: &str,
+        txn_id: &str,
+        event_id: &str,
+        key: &str,
+    ) -> Result<Event, Error> {
+        let body = json!({
+            "m.relates_to": {
+                "rel_type": "m.annotation",
+                "event_id": event_id,
+                "key": key,
+            },
+        })
+        .to_string();
+
+        self.send_event(room, "m.reaction", txn_id, body).await
+    }
+
+    pub async fn redact_event(
+        &self,
+        room: &str,
+        event_id: &str,
+        txn_id: &str,
+        reason: Option<&str>,
+    ) -> Result<Event, Error> {
+        let body = match reason {
+            Some(reason) => json!({ "reason": reason }).to_string(),
+            None => json!({}).to_string(),
+        };
+
+        let url = format!(
+            "{}/_matrix/client/r0/rooms/{}/redact/{}/{}",
+            self.base_url(), room, event_id, txn_id
+        );
+        self.put_transaction(url, body).await
+    }
+
     pub async fn send_message(
         &self,
         room: &str,
+        txn_id: &str,
         content: &str,
         formatted: Option<Arc<String>>,
+        reply_to: Option<&ReplyTo>,
     ) -> Result<Event, Error> {
-        let body = if let Some(formatted) = formatted {
-            json!({
+        let body = match (reply_to, formatted) {
+            (Some(reply_to), formatted) => {
+                let formatted = match formatted {
+                    Some(v) => (*v).clone(),
+                    None => escape_html(content),
+                };
+
+                json!({
+                    "msgtype": "m.text",
+                    "body": reply_to.fallback_body(content),
+                    "format": "org.matrix.custom.html",
+                    "formatted_body": reply_to.fallback_html(room, &formatted),
+                    "m.relates_to": {
+                        "m.in_reply_to": {
+                            "event_id": reply_to.event_id,
+                        },
+                    },
+                })
+                .to_string()
+            }
+
+            (None, Some(formatted)) => json!({
                 "msgtype": "m.text",
                 "body": content,
                 "format": "org.matrix.custom.html",
                 "formatted_body": formatted,
-            })
-            .to_string()
-        } else {
-            json!({
+            })
+            .to_string(),
+
+            (None, None) => json!({
                 "msgtype": "m.text",
                 "body": content,
             })
-            .to_string()
+            .to_string(),
         };
 
-        let event = self
-            .client
-            .post(format!(
-                "https://{}/_matrix/client/r0/rooms/{}/send/m.room.message",
-                self.homeserver, room
-            ))
-            .body(body)
-            .bearer_auth(&self.access_code)
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
-        Ok(serde_json::from_str::<Value>(&event).and_then(|v| ijson::from_value(&v)).unwrap())
+        self.send_event(room, "m.room.message", txn_id, body).await
     }
 
     pub async fn edit_message(
         &self,
         room: &str,
+        txn_id: &str,
         event_id: &str,
         content: &str,
         formatted: Option<Arc<String>>,
@@ -211,113 +1219,514 @@ impl MatrixClient {
             .to_string()
         };
 
-        let event = self
+        self.send_event(room, "m.room.message", txn_id, body).await
+    }
+
+    pub async fn upload_filter(&self, filter: &Filter) -> Result<Arc<String>, Error> {
+        let response = self
             .client
             .post(format!(
-                "https://{}/_matrix/client/r0/rooms/{}/send/m.room.message",
-                self.homeserver, room
+                "{}/_matrix/client/r0/user/{}/filter",
+                self.base_url(), self.user_id()
             ))
-            .body(body)
-            .bearer_auth(&self.access_code)
+            .body(filter.to_json())
+            .bearer_auth(self.access_token())
             .send()
-            .await?
-            .error_for_status()?
-            .text()
             .await?;
-        Ok(serde_json::from_str::<Value>(&event).and_then(|v| ijson::from_value(&v)).unwrap())
+        let response = text(response).await?;
+        Ok(parse::<FilterId>(&response)?.filter_id)
     }
 
-    async fn get_name(&self, room: &str) -> Option<Arc<String>> {
-        let name = self
-            .client
-            .get(format!(
-                "https://{}/_matrix/client/r0/rooms/{}/state/m.room.name",
-                self.homeserver, room
-            ))
-            .bearer_auth(&self.access_code)
-            .send()
-            .await
-            .ok()?;
-        if name.status() == 200 {
-            Some(Arc::new(String::from(
-                serde_json::from_str::<Value>(&name.text().await.ok()?).ok()?.get("name")?.as_string()?.as_str(),
-            )))
-        } else {
-            let name = self
-                .client
-                .get(format!(
-                    "https://{}/_matrix/client/r0/rooms/{}/state/m.room.canonical_alias",
-                    self.homeserver, room
-                ))
-                .bearer_auth(&self.access_code)
-                .send()
-                .await
-                .ok()?;
-
-            if name.status() == 200 {
-                Some(Arc::new(String::from(
-                    serde_json::from_str::<Value>(&name.text().await.ok()?).ok()?.get("alias")?
-                        .as_string()?.as_str(),
-                )))
-            } else {
-                None
-            }
+    pub async fn filter_id(&self, filter: &Filter) -> Result<Arc<String>, Error> {
+        let json = filter.to_json();
+        let cached = self.filters.read().unwrap().get(&json).cloned();
+        if let Some(id) = cached {
+            return Ok(id);
         }
+
+        let id = self.upload_filter(filter).await?;
+        self.filters.write().unwrap().insert(json, id.clone());
+        Ok(id)
     }
 
     pub async fn get_state(
         &self,
         since: Option<Arc<String>>,
-        filter: Option<Arc<String>>,
+        filter: Option<&Filter>,
+        timeout: Option<Duration>,
     ) -> Result<SyncState, Error> {
+        let state = self.get_sync(since, filter, timeout).await?;
+        match tokio::task::spawn_blocking(move || parse_sync(&state)).await {
+            Ok(v) => v,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    // the raw response body, for anything that wants to keep it around
+    pub async fn get_sync(
+        &self,
+        since: Option<Arc<String>>,
+        filter: Option<&Filter>,
+        timeout: Option<Duration>,
+    ) -> Result<String, Error> {
         let mut queries = vec![];
         if let Some(since) = since {
             queries.push(("since", since));
         }
         if let Some(filter) = filter {
-            queries.push(("filter", filter));
+            queries.push(("filter", self.filter_id(filter).await?));
         }
 
-        let state = self
+        let mut request = self
             .client
             .get(format!(
-                "https://{}/_matrix/client/r0/sync",
-                self.homeserver
-            ))
+                "{}/_matrix/client/r0/sync",
+                self.base_url()
+            ));
+        if let Some(timeout) = timeout {
+            queries.push(("timeout", Arc::new(timeout.as_millis().to_string())));
+
+            // give the server some slack, but don't wait forever on a connection that silently died
+            request = request.timeout(timeout + Duration::from_secs(30));
+        }
+
+        let response = request
             .query(&queries)
-            .bearer_auth(&self.access_code)
+            .bearer_auth(self.access_token())
             .send()
-            .await?
-            .error_for_status()?
-            .text()
             .await?;
+        text(response).await
+    }
 
-        let mut state: SyncState = match tokio::task::spawn_blocking(move|| serde_json::from_str::<Value>(&state).and_then(|v| ijson::from_value::<SyncState>(&v))).await {
-            Ok(Ok(v)) => v,
-            Ok(Err(e)) => {
-                panic!("oh no: {}", e);
-            }
-            Err(e) => {
-                panic!("oh no: {}", e);
-            }
+    pub async fn set_typing(&self, room: &str, typing: bool, timeout_ms: u64) -> Result<(), Error> {
+        let body = if typing {
+            json!({ "typing": true, "timeout": timeout_ms }).to_string()
+        } else {
+            json!({ "typing": false }).to_string()
         };
 
-        if let Some(rooms) = &mut state.rooms {
-            if let Some(join) = &mut rooms.join {
-                for (id, joined) in join.iter_mut() {
-                    joined.name = if let Some(v) = self.get_name(id).await {
-                        Some(v)
-                    } else {
-                        joined.summary.get("m.heroes").map(|v| v.as_array().unwrap().iter().map(|v| v.as_string().unwrap().as_str()).collect::<Vec<&str>>().join(", ")).map(Arc::new)
-                    }
-                }
-            }
+        let response = self
+            .client
+            .put(format!(
+                "{}/_matrix/client/r0/rooms/{}/typing/{}",
+                self.base_url(), room, self.user_id()
+            ))
+            .body(body)
+            .timeout(QUICK_TIMEOUT)
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        text(response).await.map(|_| ())
+    }
+
+    async fn membership(&self, room: &str, action: &str, user: &str, reason: Option<&str>) -> Result<(), Error> {
+        let mut body = json!({ "user_id": user });
+        if let Some(reason) = reason {
+            body["reason"] = json!(reason);
         }
 
-        Ok(state)
+        let response = self
+            .client
+            .post(format!(
+                "{}/_matrix/client/r0/rooms/{}/{}",
+                self.base_url(), room, action
+            ))
+            .body(body.to_string())
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        text(response).await.map(|_| ())
+    }
+
+    pub async fn invite_user(&self, room: &str, user: &str, reason: Option<&str>) -> Result<(), Error> {
+        self.membership(room, "invite", user, reason).await
+    }
+
+    pub async fn kick_user(&self, room: &str, user: &str, reason: Option<&str>) -> Result<(), Error> {
+        self.membership(room, "kick", user, reason).await
+    }
+
+    pub async fn ban_user(&self, room: &str, user: &str, reason: Option<&str>) -> Result<(), Error> {
+        self.membership(room, "ban", user, reason).await
+    }
+
+    pub async fn unban_user(&self, room: &str, user: &str, reason: Option<&str>) -> Result<(), Error> {
+        self.membership(room, "unban", user, reason).await
+    }
+
+    pub async fn send_receipt(&self, room: &str, receipt_type: ReceiptType, event_id: &str) -> Result<(), Error> {
+        let response = self
+            .client
+            .post(format!(
+                "{}/_matrix/client/r0/rooms/{}/receipt/{}/{}",
+                self.base_url(), room, receipt_type.as_str(), event_id
+            ))
+            .body("{}")
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        text(response).await.map(|_| ())
+    }
+
+    pub async fn set_read_markers(
+        &self,
+        room: &str,
+        fully_read: &str,
+        read: Option<&str>,
+        read_private: Option<&str>,
+    ) -> Result<(), Error> {
+        // m.fully_read isn't a receipt, so it can only be moved through here
+        let mut body = HashMap::new();
+        body.insert("m.fully_read", fully_read);
+        if let Some(read) = read {
+            body.insert(ReceiptType::Read.as_str(), read);
+        }
+        if let Some(read_private) = read_private {
+            body.insert(ReceiptType::ReadPrivate.as_str(), read_private);
+        }
+
+        let response = self
+            .client
+            .post(format!(
+                "{}/_matrix/client/r0/rooms/{}/read_markers",
+                self.base_url(), room
+            ))
+            .body(serde_json::to_string(&body)?)
+            .timeout(QUICK_TIMEOUT)
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        text(response).await.map(|_| ())
+    }
+
+    pub async fn set_room_tag(&self, room: &str, tag: &str, order: Option<f64>) -> Result<(), Error> {
+        let body = match order {
+            Some(order) => json!({ "order": order }).to_string(),
+            None => json!({}).to_string(),
+        };
+
+        let response = self
+            .client
+            .put(format!(
+                "{}/_matrix/client/r0/user/{}/rooms/{}/tags/{}",
+                self.base_url(), self.user_id(), room, escape_path_segment(tag)
+            ))
+            .body(body)
+            .timeout(QUICK_TIMEOUT)
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        text(response).await.map(|_| ())
+    }
+
+    pub async fn delete_room_tag(&self, room: &str, tag: &str) -> Result<(), Error> {
+        let response = self
+            .client
+            .delete(format!(
+                "{}/_matrix/client/r0/user/{}/rooms/{}/tags/{}",
+                self.base_url(), self.user_id(), room, escape_path_segment(tag)
+            ))
+            .timeout(QUICK_TIMEOUT)
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        text(response).await.map(|_| ())
+    }
+
+    pub async fn get_account_data(&self, event_type: &str) -> Result<Option<Value>, Error> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/_matrix/client/r0/user/{}/account_data/{}",
+                self.base_url(), self.user_id(), event_type
+            ))
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        match text(response).await {
+            Ok(v) => Ok(Some(parse(&v)?)),
+            Err(e) if e.errcode() == Some(&ErrorCode::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn set_account_data(&self, event_type: &str, content: String) -> Result<(), Error> {
+        let response = self
+            .client
+            .put(format!(
+                "{}/_matrix/client/r0/user/{}/account_data/{}",
+                self.base_url(), self.user_id(), event_type
+            ))
+            .body(content)
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        text(response).await.map(|_| ())
+    }
+
+    pub async fn get_state_event(&self, room: &str, event_type: &str, state_key: &str) -> Result<Option<Value>, Error> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/_matrix/client/r0/rooms/{}/state/{}/{}",
+                self.base_url(), room, event_type, state_key
+            ))
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        match text(response).await {
+            Ok(v) => Ok(Some(parse(&v)?)),
+            Err(e) if e.errcode() == Some(&ErrorCode::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn send_state_event(&self, room: &str, event_type: &str, state_key: &str, content: String) -> Result<Arc<String>, Error> {
+        let response = self
+            .client
+            .put(format!(
+                "{}/_matrix/client/r0/rooms/{}/state/{}/{}",
+                self.base_url(), room, event_type, state_key
+            ))
+            .body(content)
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        let response = text(response).await?;
+        Ok(parse::<Event>(&response)?.event_id)
+    }
+
+    pub async fn create_room(&self, options: &CreateRoom) -> Result<Arc<String>, Error> {
+        let mut body = json!({
+            "is_direct": options.is_direct,
+            "invite": options.invite,
+        });
+
+        if let Some(name) = &options.name {
+            body["name"] = json!(name);
+        }
+        if let Some(topic) = &options.topic {
+            body["topic"] = json!(topic);
+        }
+        if let Some(alias) = &options.alias {
+            body["room_alias_name"] = json!(alias);
+        }
+        if let Some(preset) = options.preset {
+            body["preset"] = json!(preset.as_str());
+        }
+        if let Some(room_type) = &options.room_type {
+            body["creation_content"] = json!({ "type": room_type });
+        }
+        if options.encrypted {
+            body["initial_state"] = json!([{
+                "type": "m.room.encryption",
+                "state_key": "",
+                "content": { "algorithm": "m.megolm.v1.aes-sha2" },
+            }]);
+        }
+
+        let response = self
+            .client
+            .post(format!(
+                "{}/_matrix/client/r0/createRoom",
+                self.base_url()
+            ))
+            .body(body.to_string())
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        let response = text(response).await?;
+        Ok(parse::<CreatedRoom>(&response)?.room_id)
+    }
+
+    pub async fn create_direct_room(&self, user: &str) -> Result<Arc<String>, Error> {
+        let room = self
+            .create_room(&CreateRoom {
+                invite: vec![String::from(user)],
+                is_direct: true,
+                preset: Some(RoomPreset::TrustedPrivate),
+                ..CreateRoom::default()
+            })
+            .await?;
+
+        // m.direct is replaced wholesale, so add to whatever is already there
+        let mut direct: HashMap<String, Vec<String>> = match self.get_account_data("m.direct").await? {
+            Some(v) => ijson::from_value(&v)?,
+            None => HashMap::new(),
+        };
+        direct.entry(String::from(user)).or_default().push((*room).clone());
+        self.set_account_data("m.direct", serde_json::to_string(&direct)?).await?;
+
+        Ok(room)
+    }
+
+    pub async fn fetch_display_name(&self, user: &str) -> Result<Option<String>, Error> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/_matrix/client/r0/profile/{}/displayname",
+                self.base_url(), user,
+            ))
+            .send()
+            .await?;
+        let response = text(response).await?;
+        Ok(parse::<Value>(&response)?.get("displayname").and_then(|v| v.as_string()).map(|v| v.to_string()))
+    }
+
+    pub async fn get_space_hierarchy(&self, space: &str, from: Option<&str>, limit: Option<u64>) -> Result<SpaceHierarchy, Error> {
+        let mut queries = vec![];
+        if let Some(from) = from {
+            queries.push(("from", String::from(from)));
+        }
+        if let Some(limit) = limit {
+            queries.push(("limit", limit.to_string()));
+        }
+
+        let response = self
+            .client
+            .get(format!(
+                "{}/_matrix/client/v1/rooms/{}/hierarchy",
+                self.base_url(), space
+            ))
+            .query(&queries)
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        let response = text(response).await?;
+        parse(&response)
+    }
+
+    pub async fn join_room(&self, room: &str, via: &[Arc<String>]) -> Result<Arc<String>, Error> {
+        let queries: Vec<(&str, &str)> = via.iter().map(|v| ("server_name", v.as_str())).collect();
+        let response = self
+            .client
+            .post(format!(
+                "{}/_matrix/client/r0/join/{}",
+                self.base_url(), room.replace('#', "%23")
+            ))
+            .query(&queries)
+            .body("{}")
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        let response = text(response).await?;
+        Ok(parse::<JoinedRoomId>(&response)?.room_id)
+    }
+
+    pub async fn leave_room(&self, room: &str) -> Result<(), Error> {
+        let response = self
+            .client
+            .post(format!(
+                "{}/_matrix/client/r0/rooms/{}/leave",
+                self.base_url(), room
+            ))
+            .body("{}")
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        text(response).await.map(|_| ())
+    }
+
+    pub async fn forget_room(&self, room: &str) -> Result<(), Error> {
+        let response = self
+            .client
+            .post(format!(
+                "{}/_matrix/client/r0/rooms/{}/forget",
+                self.base_url(), room
+            ))
+            .body("{}")
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        text(response).await.map(|_| ())
+    }
+
+    // rejecting is just leaving, but there's no history worth keeping around afterwards
+    pub async fn reject_invite(&self, room: &str) -> Result<(), Error> {
+        self.leave_room(room).await?;
+        self.forget_room(room).await
+    }
+
+    pub async fn upload_media(&self, content_type: &str, filename: &str, data: Vec<u8>) -> Result<String, Error> {
+        let response = self
+            .client
+            .post(format!(
+                "{}/_matrix/media/v3/upload",
+                self.base_url()
+            ))
+            .query(&[("filename", filename)])
+            .header("Content-Type", content_type)
+            .body(data)
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        let response = text(response).await?;
+        let response: UploadResponse = parse(&response)?;
+        Ok(response.content_uri)
+    }
+
+    pub async fn send_image(
+        &self,
+        room: &str,
+        txn_id: &str,
+        url: &str,
+        attachment: &Attachment,
+        width: u64,
+        height: u64,
+    ) -> Result<Event, Error> {
+        let body = json!({
+            "msgtype": "m.image",
+            "body": attachment.filename,
+            "url": url,
+            "info": {
+                "mimetype": attachment.mimetype,
+                "size": attachment.size,
+                "w": width,
+                "h": height,
+            },
+        })
+        .to_string();
+
+        self.send_event(room, "m.room.message", txn_id, body).await
+    }
+
+    pub async fn send_file(
+        &self,
+        room: &str,
+        txn_id: &str,
+        url: &str,
+        attachment: &Attachment,
+    ) -> Result<Event, Error> {
+        let body = json!({
+            "msgtype": "m.file",
+            "body": attachment.filename,
+            "filename": attachment.filename,
+            "url": url,
+            "info": {
+                "mimetype": attachment.mimetype,
+                "size": attachment.size,
+            },
+        })
+        .to_string();
+
+        self.send_event(room, "m.room.message", txn_id, body).await
+    }
+
+    pub async fn get_event(&self, room_id: &str, event_id: &str) -> Result<RoomEvent, Error> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/_matrix/client/r0/rooms/{}/event/{}",
+                self.base_url(), room_id, event_id
+            ))
+            .bearer_auth(self.access_token())
+            .send()
+            .await?;
+        let event = text(response).await?;
+        parse(&event)
     }
 
-    pub async fn get_room_messages(&self, room_id: &str, from: &str, dir: RoomDirection, to: Option<&String>, limit: Option<u64>, filter: Option<Arc<String>>) -> Result<RoomMessages, Error> {
+    pub async fn get_room_messages(&self, room_id: &str, from: &str, dir: RoomDirection, to: Option<&String>, limit: Option<u64>, filter: Option<&RoomEventFilter>) -> Result<RoomMessages, Error> {
         let dir = match dir {
             RoomDirection::Forwards => "f",
             RoomDirection::Backwards => "b",
@@ -327,38 +1736,31 @@ impl MatrixClient {
             Some(v) => format!("{}", v),
             None => String::from("10"),
         };
-        let filter_ = match filter.as_ref() {
-            Some(v) => v.as_str(),
-            None => "",
+        let filter = match filter {
+            Some(v) => v.to_json(),
+            None => String::new(),
         };
-        let mut queries = vec![("from", from), ("dir", dir), ("limit", &limit), ("filter", filter_)];
+        let mut queries = vec![("from", from), ("dir", dir), ("limit", &limit), ("filter", &filter)];
         if let Some(to) = to {
             queries.push(("to", to));
         }
 
-        let state = self
+        let response = self
             .client
             .get(format!(
-                "https://{}/_matrix/client/r0/rooms/{}/messages",
-                self.homeserver,
+                "{}/_matrix/client/r0/rooms/{}/messages",
+                self.base_url(),
                 room_id,
             ))
             .query(&queries)
-            .bearer_auth(&self.access_code)
+            .bearer_auth(self.access_token())
             .send()
-            .await?
-            .error_for_status()?
-            .text()
             .await?;
+        let state = text(response).await?;
 
-        let state = match tokio::task::spawn_blocking(move|| serde_json::from_str::<Value>(&state).and_then(|v| ijson::from_value::<RoomMessages>(&v))).await {
-            Ok(Ok(v)) => v,
-            Ok(Err(e)) => {
-                panic!("oh no: {}", e);
-            }
-            Err(e) => {
-                panic!("oh no: {}", e);
-            }
+        let state = match tokio::task::spawn_blocking(move || parse::<RoomMessages>(&state)).await {
+            Ok(v) => v?,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
         };
 
         Ok(state)
@@ -371,16 +1773,16 @@ impl MatrixClient {
         width: u64,
         height: u64,
     ) -> Result<Content, Error> {
-        let mut response = self
+        let response = self
             .client
             .get(format!(
-                "https://{}/_matrix/media/r0/thumbnail/{}/{}",
-                self.homeserver, server_name, media_id,
+                "{}/_matrix/media/r0/thumbnail/{}/{}",
+                self.base_url(), server_name, media_id,
             ))
             .query(&[("width", width), ("height", height)])
             .send()
-            .await?
-            .error_for_status()?;
+            .await?;
+        let mut response = check_status(response).await?;
         let mut content = Content {
             type_: Arc::new(String::from(
                 response
@@ -406,18 +1808,51 @@ impl MatrixClient {
         Ok(content)
     }
 
+    pub async fn download_mxc(
+        &self,
+        server_name: &str,
+        media_id: &str,
+        path: &Path,
+        mut progress: impl FnMut(u64, Option<u64>),
+    ) -> Result<(), Error> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/_matrix/media/r0/download/{}/{}",
+                self.base_url(), server_name, media_id,
+            ))
+            .send()
+            .await?;
+        let mut response = check_status(response).await?;
+        let total = response.content_length();
+
+        // write to a temporary file so an interrupted download never looks finished
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        let mut file = tokio::fs::File::create(&partial).await?;
+        let mut downloaded = 0;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+            downloaded += chunk.len() as u64;
+            progress(downloaded, total);
+        }
+
+        file.flush().await?;
+        tokio::fs::rename(&partial, path).await?;
+        Ok(())
+    }
+
     pub async fn fetch_avatar_url(&self, user: &str) -> Result<String, Error> {
         let response = self
             .client
             .get(format!(
-                "https://{}/_matrix/client/r0/profile/{}/avatar_url",
-                self.homeserver, user,
+                "{}/_matrix/client/r0/profile/{}/avatar_url",
+                self.base_url(), user,
             ))
             .send()
-            .await?
-            .error_for_status()?
-            .text()
             .await?;
-        Ok(serde_json::from_str::<Value>(&response).unwrap().get("avatar_url").and_then(|v| v.as_string()).map(IString::as_str).unwrap_or_default().to_string())
+        let response = text(response).await?;
+        Ok(parse::<Value>(&response)?.get("avatar_url").and_then(|v| v.as_string()).map(IString::as_str).unwrap_or_default().to_string())
     }
 }
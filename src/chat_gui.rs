@@ -1,47 +1,160 @@
+use std::ops::Range;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use druid::im::{HashMap, Vector};
 use druid::keyboard_types::Key;
+use druid::menu::{Menu, MenuItem};
 use druid::text::{Attribute, RichText, RichTextBuilder};
 use druid::widget::{Axis, CrossAxisAlignment, LineBreaking, ListIter};
-use druid::{Color, Data, Env, Event, EventCtx, FontFamily, FontStyle, FontWeight, ImageBuf, Lens, LensExt, Point, Selector, TextAlignment, Widget, WidgetExt, WidgetId, widget};
+use druid::{commands, Application, BoxConstraints, Color, Data, FileDialogOptions, Env, Event, EventCtx, FontFamily, FontStyle, FontWeight, ImageBuf, LayoutCtx, Lens, LensExt, LifeCycle, LifeCycleCtx, PaintCtx, Point, RenderContext, Selector, Size, TextAlignment, TextLayout, UpdateCtx, Vec2, Widget, WidgetExt, WidgetId, WindowDesc, TimerToken, theme, widget};
 use kuchiki::traits::TendrilSink;
 use kuchiki::{NodeData, NodeRef};
-use reqwest::Error;
 use serde_json::json;
 use ijson::{IString, IValue as Value};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TrySendError;
 // use uwuifier::uwuify_str_sse;
 
-use super::chat::{RoomEvent, RoomMessages, SyncState};
+use super::chat::{self, AccountDataEvent, EphemeralEvent, ReplyTo, RoomEvent, RoomMessages, SyncState};
+use super::error::{Error, ErrorCode};
 use super::markdown;
+use super::widgets::{self, ImageViewer};
 
-pub const SYNC: Selector<SyncState> = Selector::new("uwutalk.matrix.sync");
-pub const SYNC_FAIL: Selector<Error> = Selector::new("uwutalk.matrix.fail.sync");
+pub const LOGGED_IN: Selector<Arc<String>> = Selector::new("uwutalk.matrix.logged_in");
+pub const LOGIN_FAIL: Selector<Error> = Selector::new("uwutalk.matrix.fail.login");
+pub const MESSAGE_SENT: Selector<(Arc<String>, Arc<String>, Arc<String>)> = Selector::new("uwutalk.matrix.message_sent");
+pub const MESSAGE_SEND_FAIL: Selector<(Arc<String>, Arc<String>, Error)> = Selector::new("uwutalk.matrix.fail.message_sent");
+// syncs carry the session they were started in, so one that outlives a logout gets dropped
+pub const SYNC: Selector<(u64, SyncState)> = Selector::new("uwutalk.matrix.sync");
+pub const SYNC_FAIL: Selector<(u64, Error)> = Selector::new("uwutalk.matrix.fail.sync");
 pub const FETCH_FROM_ROOM: Selector<(Arc<String>, RoomMessages)> = Selector::new("uwutalk.matrix.fetch_from_room");
-pub const FETCH_FROM_ROOM_FAIL: Selector<Error> = Selector::new("uwutalk.matrix.fail.fetch_from_room");
+pub const FETCH_FROM_ROOM_FAIL: Selector<(Arc<String>, Error)> = Selector::new("uwutalk.matrix.fail.fetch_from_room");
+pub const DOWNLOAD_PROGRESS: Selector<(Arc<String>, f64)> = Selector::new("uwutalk.matrix.download_progress");
+pub const DOWNLOAD_DONE: Selector<(Arc<String>, ImageBuf, Arc<PathBuf>)> = Selector::new("uwutalk.matrix.download_done");
+pub const DOWNLOAD_FAIL: Selector<(Arc<String>, Arc<String>)> = Selector::new("uwutalk.matrix.fail.download");
+pub const DISPLAY_NAME: Selector<(Arc<String>, Arc<String>)> = Selector::new("uwutalk.matrix.display_name");
+pub const DIRECT_CREATED: Selector<(Arc<String>, Arc<String>)> = Selector::new("uwutalk.matrix.direct_created");
+pub const SPACE_HIERARCHY: Selector<(Arc<String>, Vec<chat::SpaceRoom>)> = Selector::new("uwutalk.matrix.space_hierarchy");
+pub const ROOM_CREATED: Selector<(Arc<String>, Arc<String>, bool)> = Selector::new("uwutalk.matrix.room_created");
+pub const ROOM_CREATE_FAIL: Selector<Arc<String>> = Selector::new("uwutalk.matrix.room_create_fail");
+pub const ROOM_STATE: Selector<(Arc<String>, Vec<(String, Value)>)> = Selector::new("uwutalk.matrix.room_state");
+pub const ROOM_STATE_FAIL: Selector<(Arc<String>, Arc<String>)> = Selector::new("uwutalk.matrix.room_state_fail");
+pub const STATE_SEND_FAIL: Selector<(Arc<String>, &'static str, Arc<String>)> = Selector::new("uwutalk.matrix.state_send_fail");
+pub const MODERATE_FAIL: Selector<(Arc<String>, Moderation, Arc<String>, Arc<String>)> = Selector::new("uwutalk.matrix.moderate_fail");
+pub const ROOM_LEFT: Selector<Arc<String>> = Selector::new("uwutalk.matrix.room_left");
+pub const MEMBERSHIP_FAIL: Selector<(Arc<String>, &'static str, Arc<String>)> = Selector::new("uwutalk.matrix.membership_fail");
+pub const FETCH_EVENT: Selector<(Arc<String>, RoomEvent)> = Selector::new("uwutalk.matrix.fetch_event");
+pub const MARK_READ_FAIL: Selector<Arc<String>> = Selector::new("uwutalk.matrix.fail.mark_read");
+pub const FETCH_EVENT_FAIL: Selector<(Arc<String>, Arc<String>, Error)> = Selector::new("uwutalk.matrix.fail.fetch_event");
+pub const REACTION_FAIL: Selector<(Arc<String>, Arc<String>, Arc<String>)> = Selector::new("uwutalk.matrix.fail.reaction");
 pub const FETCH_THUMBNAIL: Selector<ImageBuf> = Selector::new("uwutalk.matrix.fetch_thumbnail");
 pub const FETCH_THUMBNAIL_FAIL: Selector<Error> = Selector::new("uwutalk.matrix.fail.fetch_thumbnail");
 const SCROLLED: Selector<()> = Selector::new("uwutalk.matrix.scrolled");
+const DISCARD_MESSAGE: Selector<(Arc<String>, Arc<String>)> = Selector::new("uwutalk.matrix.discard_message");
+const OPEN_IMAGE: Selector<(Arc<String>, Arc<String>)> = Selector::new("uwutalk.matrix.open_image");
+const FOCUS_SWITCHER: Selector = Selector::new("uwutalk.gui.focus_switcher");
+const SWITCH_TO: Selector<Arc<String>> = Selector::new("uwutalk.gui.switch_to");
+const SELECT_SPACE: Selector<Option<Arc<String>>> = Selector::new("uwutalk.gui.select_space");
+const ACCEPT_INVITE: Selector<Arc<String>> = Selector::new("uwutalk.gui.accept_invite");
+const DECLINE_INVITE: Selector<Arc<String>> = Selector::new("uwutalk.gui.decline_invite");
+const CREATE_ROOM: Selector = Selector::new("uwutalk.gui.create_room");
+const CANCEL_NEW_ROOM: Selector = Selector::new("uwutalk.gui.cancel_new_room");
+const RECONNECT: Selector = Selector::new("uwutalk.gui.reconnect");
+const SAVE_SETTINGS: Selector = Selector::new("uwutalk.gui.save_settings");
+const CLOSE_SETTINGS: Selector = Selector::new("uwutalk.gui.close_settings");
+const REPLY: Selector<Replying> = Selector::new("uwutalk.matrix.reply");
+const TOGGLE_REACTION: Selector<(Arc<String>, Arc<String>, Arc<String>)> = Selector::new("uwutalk.matrix.toggle_reaction");
+const QUICK_REACTIONS: [&str; 6] = ["👍", "❤️", "😂", "😮", "😢", "🎉"];
+
+// a single byte, so positions in the drawn mask count characters of the password
+const PASSWORD_MASK: char = '*';
+const PASSWORD_PADDING: Vec2 = Vec2::new(6.0, 4.0);
 const LINK: Selector<Arc<str>> = Selector::new("uwutalk.matrix.link");
 
 pub enum Syncing {
     Quit,
-    ClientSync(Arc<String>, Arc<String>),
-    FetchFromRoom(Arc<String>, Arc<String>, Arc<String>)
+    ClientSync(u64, Arc<String>, chat::Filter),
+    FetchFromRoom(Arc<String>, Arc<String>, chat::RoomEventFilter),
+    FetchEvent(Arc<String>, Arc<String>),
+    FetchDisplayNames(Vec<Arc<String>>),
+    FetchHierarchy(Vec<Arc<String>>),
+    FetchRoomState(Arc<String>),
 }
 
 pub enum UserAction {
     Quit,
-    SendMessage(Arc<String>, Arc<String>, Arc<String>),
-    EditMessage(Arc<String>, Arc<String>, Arc<String>, Arc<String>),
+    Login(Arc<String>, Arc<String>, Arc<String>),
+    Logout,
+    SendMessage(Arc<String>, Arc<String>, Arc<String>, Arc<String>, Option<ReplyTo>),
+    SendFile(Arc<String>, Arc<String>, Arc<PathBuf>),
+    SetTyping(Arc<String>, bool),
+    MarkRead(Arc<String>, Arc<String>),
+    SetTag(Arc<String>, Arc<String>),
+    RemoveTag(Arc<String>, Arc<String>),
+    CreateDirect(Arc<String>),
+    CreateRoom(chat::CreateRoom),
+    JoinRoom(Arc<String>, Vec<Arc<String>>),
+    LeaveRoom(Arc<String>),
+    RejectInvite(Arc<String>),
+    Moderate(Arc<String>, Moderation, Arc<String>, Option<Arc<String>>),
+    SetState(Arc<String>, &'static str, String),
+    EditMessage(Arc<String>, Arc<String>, Arc<String>, Arc<String>, Arc<String>),
+    RedactEvent(Arc<String>, Arc<String>, Option<Arc<String>>),
+    SendReaction(Arc<String>, Arc<String>, Arc<String>),
+    RemoveReaction(Arc<String>, Arc<String>, Arc<String>, Arc<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Moderation {
+    Invite,
+    Kick,
+    Ban,
+    Unban,
+}
+
+impl Moderation {
+    fn from_command(command: &str) -> Option<Moderation> {
+        match command {
+            "invite" => Some(Moderation::Invite),
+            "kick" => Some(Moderation::Kick),
+            "ban" => Some(Moderation::Ban),
+            "unban" => Some(Moderation::Unban),
+            _ => None,
+        }
+    }
+
+    // only lines naming a command we know are taken as one, so "/shrug" and paths still get sent
+    fn from_line(line: &str) -> Option<Moderation> {
+        Moderation::from_command(line.strip_prefix('/')?.split(' ').next()?)
+    }
+
+    fn command(&self) -> &'static str {
+        match self {
+            Moderation::Invite => "invite",
+            Moderation::Kick => "kick",
+            Moderation::Ban => "ban",
+            Moderation::Unban => "unban",
+        }
+    }
+
+    fn allowed(&self, levels: &chat::PowerLevels, user: &str, target: &str) -> bool {
+        match self {
+            Moderation::Invite => levels.can_invite(user),
+            Moderation::Kick => levels.can_kick(user, target),
+            Moderation::Ban => levels.can_ban(user, target),
+            Moderation::Unban => levels.can_unban(user, target),
+        }
+    }
 }
 
 pub enum MediaFetch {
     Quit,
     FetchThumbnail(Arc<String>, WidgetId, u64, u64),
     AvatarFetch(Arc<String>, WidgetId),
+    Download(Arc<String>),
+    Save(Arc<PathBuf>, PathBuf),
 }
 
 #[derive(Clone)]
@@ -57,11 +170,265 @@ struct Channel {
     name: Arc<String>,
     messages: Vector<Message>,
     unresolved_edits: Vector<Edit>,
+    unresolved_redactions: Vector<Arc<String>>,
+    unresolved_reactions: Vector<Annotation>,
+
+    // (target, key, whether ours should end up there) for toggles the server hasn't echoed yet
+    pending_reactions: Vector<(Arc<String>, Arc<String>, bool)>,
     prev_batch: Arc<String>,
     first_batch: Arc<String>,
     bottom: bool,
     fetching_old: bool,
+    fetch_failed: bool,
     top: bool,
+    typing: Vector<Arc<String>>,
+    receipts: HashMap<Arc<String>, Arc<String>>,
+    read_up_to: Arc<String>,
+
+    // where the server last echoed our own receipt. until it catches up with read_up_to, the
+    // unread counts it sends are from before we read anything and would bring badges back
+    read_echoed: Arc<String>,
+    highlight_count: i64,
+    notification_count: i64,
+    last_activity: u64,
+    aliases: Vector<Arc<String>>,
+    tags: HashMap<Arc<String>, Option<f64>>,
+    dm: Option<DirectInfo>,
+    is_space: bool,
+    power_levels: Arc<chat::PowerLevels>,
+
+    #[data(ignore)]
+    naming: chat::RoomNameState,
+}
+
+impl Channel {
+    fn new(id: Arc<String>, name: Arc<String>) -> Channel {
+        Channel {
+            id,
+            name,
+            messages: Vector::new(),
+            unresolved_edits: Vector::new(),
+            unresolved_redactions: Vector::new(),
+            unresolved_reactions: Vector::new(),
+            pending_reactions: Vector::new(),
+            prev_batch: Arc::new(String::new()),
+            first_batch: Arc::new(String::new()),
+            bottom: true,
+            fetching_old: false,
+            fetch_failed: false,
+            top: false,
+            typing: Vector::new(),
+            receipts: HashMap::new(),
+            read_up_to: Arc::new(String::new()),
+            read_echoed: Arc::new(String::new()),
+            highlight_count: 0,
+            notification_count: 0,
+            last_activity: 0,
+            aliases: Vector::new(),
+            tags: HashMap::new(),
+            dm: None,
+            is_space: false,
+            power_levels: Arc::new(chat::PowerLevels::default()),
+            naming: chat::RoomNameState::default(),
+        }
+    }
+
+    fn display_name(&self) -> Arc<String> {
+        match &self.dm {
+            Some(DirectInfo { display_name: Some(name), .. }) => name.clone(),
+            Some(dm) => dm.user_id.clone(),
+            None => self.name.clone(),
+        }
+    }
+
+    fn has_tag(&self, tag: &str) -> bool {
+        self.tags.keys().any(|v| v.as_str() == tag)
+    }
+
+    fn tag_order(&self, tag: &str) -> Option<f64> {
+        self.tags.iter().find(|(k, _)| k.as_str() == tag).and_then(|(_, v)| *v)
+    }
+
+    fn set_receipt(&mut self, user: Arc<String>, event_id: Arc<String>) {
+        // receipts only move forwards, but we can only tell if both events are loaded
+        let new = self.messages.iter().position(|v| v.event_id == event_id);
+        let old = self
+            .receipts
+            .get(&user)
+            .and_then(|old| self.messages.iter().position(|v| &v.event_id == old));
+        if let (Some(new), Some(old)) = (new, old) {
+            if new < old {
+                return;
+            }
+        }
+
+        self.receipts.insert(user, event_id);
+    }
+
+    fn update_read_by(&mut self) {
+        let mut previous = HashMap::new();
+        for msg in self.messages.iter_mut() {
+            if !msg.read_by.is_empty() {
+                for receipt in std::mem::take(&mut msg.read_by) {
+                    previous.insert(receipt.user.clone(), receipt);
+                }
+            }
+        }
+
+        for (user, event_id) in self.receipts.iter() {
+            if let Some(msg) = self.messages.iter_mut().find(|v| &v.event_id == event_id) {
+                let receipt = match previous.remove(user) {
+                    Some(v) => v,
+                    None => ReadReceipt {
+                        user: user.clone(),
+                        avatar: AvatarState::Name(user.clone()),
+                        txs: msg.txs.clone(),
+                    },
+                };
+                msg.read_by.push_back(receipt);
+            }
+        }
+    }
+
+    fn add_timeline(&mut self, timeline: Vec<Message>, backfill: bool, user_id: &str) {
+        // backfilled events arrive newest first
+        let mut older = Vector::new();
+        for message in timeline {
+            self.last_activity = self.last_activity.max(message.timestamp);
+            if let Some(edit) = &message.edit {
+                self.unresolved_edits.push_back(edit.clone());
+            } else if let Some(redacts) = &message.redacts {
+                self.unresolved_redactions.push_back(redacts.clone());
+            } else if let Some(annotation) = &message.annotation {
+                self.unresolved_reactions.push_back(annotation.clone());
+            } else if message.event_type.as_str() == "m.reaction" {
+                // a reaction that has already been redacted
+            } else if backfill {
+                older.push_front(message);
+            } else {
+                self.push_message(message);
+            }
+        }
+
+        if backfill {
+            older.append(self.messages.clone());
+            self.messages = older;
+        }
+
+        self.resolve_relations(user_id);
+    }
+
+    fn resolve_relations(&mut self, user_id: &str) {
+        let messages = &mut self.messages;
+        self.unresolved_edits.retain(|edit| {
+            match messages.iter_mut().find(|v| v.event_id == edit.associated_event_id) {
+                Some(msg) => {
+                    if !msg.redacted {
+                        msg.contents = edit.contents.clone();
+                        msg.formatted = edit.formatted.clone();
+                    }
+                    false
+                }
+
+                None => true,
+            }
+        });
+
+        self.unresolved_reactions.retain(|annotation| {
+            match messages.iter_mut().find(|v| v.event_id == annotation.associated_event_id) {
+                Some(msg) => {
+                    msg.add_annotation(annotation.clone(), user_id);
+                    false
+                }
+
+                None => true,
+            }
+        });
+
+        let reactions = &mut self.unresolved_reactions;
+        self.unresolved_redactions.retain(|redacts| {
+            if let Some(msg) = messages.iter_mut().find(|v| &v.event_id == redacts) {
+                msg.redact();
+                return false;
+            }
+
+            if messages.iter_mut().any(|v| v.remove_annotation(redacts, user_id)) {
+                return false;
+            }
+
+            let count = reactions.len();
+            reactions.retain(|v| &v.event_id != redacts);
+            count == reactions.len()
+        });
+
+        self.pending_reactions.retain(|(target, key, own)| {
+            match messages.iter().find(|v| &v.event_id == target) {
+                Some(msg) => msg.reactions.iter().any(|v| &v.key == key && v.own) != *own,
+                None => false,
+            }
+        });
+
+        self.resolve_replies();
+        self.update_read_by();
+    }
+
+    fn resolve_replies(&mut self) {
+        let pending: Vec<(usize, Arc<String>)> = self
+            .messages
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| match &v.reply_to {
+                Some(parent) if v.reply_preview.is_none() && !v.fetching_reply => Some((i, parent.clone())),
+                _ => None,
+            })
+            .collect();
+
+        let mut requested = vec![];
+        let mut queue_full = false;
+        for (i, parent) in pending {
+            let preview = self.messages.iter().find(|v| v.event_id == parent).map(ReplyPreview::of);
+            if let Some(msg) = self.messages.get_mut(i) {
+                match preview {
+                    Some(preview) => msg.reply_preview = Some(preview),
+
+                    // the parent isn't loaded, so ask the homeserver for it. if there's no room left
+                    // to ask, it stays unfetched and gets picked up again once a fetch comes back
+                    None if !queue_full => {
+                        if !requested.contains(&parent) {
+                            match msg.txs.sync_tx.try_send(Syncing::FetchEvent(self.id.clone(), parent.clone())) {
+                                Ok(_) => (),
+                                Err(TrySendError::Full(_)) => {
+                                    queue_full = true;
+                                    continue;
+                                }
+                                Err(TrySendError::Closed(_)) => panic!("aaaaa"),
+                            }
+                            requested.push(parent);
+                        }
+                        msg.fetching_reply = true;
+                    }
+
+                    None => (),
+                }
+            }
+        }
+    }
+
+    fn push_message(&mut self, message: Message) {
+        // our own messages come back with the transaction id we sent them with
+        let existing = match &message.transaction_id {
+            Some(txn_id) => self
+                .messages
+                .iter_mut()
+                .find(|v| v.transaction_id.as_ref() == Some(txn_id)),
+            None => None,
+        };
+
+        match existing {
+            Some(existing) => *existing = message,
+            None => self.messages.push_back(message),
+        }
+    }
 }
 
 #[derive(Data, Clone)]
@@ -72,6 +439,23 @@ enum ThumbnailState {
     Image(Arc<ImageBuf>, u64, u64),
 }
 
+#[derive(Data, Clone)]
+struct Annotation {
+    associated_event_id: Arc<String>,
+    event_id: Arc<String>,
+    sender: Arc<String>,
+    key: Arc<String>,
+}
+
+#[derive(Data, Clone, Lens)]
+struct Reaction {
+    channel: Arc<String>,
+    target: Arc<String>,
+    key: Arc<String>,
+    annotations: Vector<Annotation>,
+    own: bool,
+}
+
 #[derive(Data, Clone)]
 struct Edit {
     associated_event_id: Arc<String>,
@@ -86,682 +470,2997 @@ enum AvatarState {
     Image(Arc<ImageBuf>),
 }
 
+#[derive(Data, Clone, Copy, PartialEq)]
+enum SendState {
+    Sent,
+    Sending,
+    Failed,
+}
+
 #[derive(Data, Clone, Lens)]
 struct Message {
+    event_type: Arc<String>,
     edit: Option<Edit>,
+    redacts: Option<Arc<String>>,
+    redacted: bool,
+    annotation: Option<Annotation>,
+    reactions: Vector<Reaction>,
+    picking_reaction: bool,
     sender: Arc<String>,
     avatar: AvatarState,
     event_id: Arc<String>,
+    transaction_id: Option<Arc<String>>,
+    send_state: SendState,
     contents: Arc<String>,
+    html: Option<Arc<String>>,
     formatted: RichText,
+    reply_to: Option<Arc<String>>,
+    reply_preview: Option<ReplyPreview>,
+    fetching_reply: bool,
     image: ThumbnailState,
+    media_url: Option<Arc<String>>,
+    read_by: Vector<ReadReceipt>,
+    timestamp: u64,
     editing_message: Arc<String>,
     editing: bool,
     channel: Arc<String>,
 
     #[data(ignore)]
-    txs: Senders,
-}
-
-#[derive(Data, Clone, Lens)]
-pub struct Chat {
-    editing_message: Arc<String>,
-    channels_hashed: HashMap<Arc<String>, Channel>,
-    channels: Vector<Arc<String>>,
-    current_channel: Arc<String>,
+    reply: Option<ReplyTo>,
 
     #[data(ignore)]
-    scroll: Option<f64>,
+    attachment: Option<Arc<PathBuf>>,
 
     #[data(ignore)]
     txs: Senders,
 }
 
-impl Chat {
-    pub fn new(sync_tx: mpsc::Sender<Syncing>, action_tx: mpsc::Sender<UserAction>, media_tx: mpsc::Sender<MediaFetch>) -> Chat {
-        Chat {
-            editing_message: Arc::new(String::new()),
-            channels_hashed: HashMap::new(),
-            channels: Vector::new(),
-            current_channel: Arc::new(String::new()),
-            scroll: None,
-            txs: Senders {
-                sync_tx,
-                action_tx,
-                media_tx,
-            },
-        }
-    }
+#[derive(Data, Clone)]
+struct DirectInfo {
+    user_id: Arc<String>,
+    display_name: Option<Arc<String>>,
+    avatar: AvatarState,
+
+    #[data(ignore)]
+    txs: Senders,
 }
 
-struct CurrentChannel {
-    channels_hashed: HashMap<Arc<String>, Channel>,
-    current_channel: Arc<String>,
+#[derive(Data, Clone)]
+struct ReadReceipt {
+    user: Arc<String>,
+    avatar: AvatarState,
+
+    #[data(ignore)]
+    txs: Senders,
 }
 
-struct CurrentChannelLens;
+#[derive(Data, Clone)]
+struct ReplyPreview {
+    sender: Arc<String>,
+    text: Arc<String>,
+}
 
-impl Lens<Chat, CurrentChannel> for CurrentChannelLens {
-    fn with<V, F: FnOnce(&CurrentChannel) -> V>(&self, data: &Chat, f: F) -> V {
-        let current = CurrentChannel {
-            channels_hashed: data.channels_hashed.clone(),
-            current_channel: data.current_channel.clone(),
+impl ReplyPreview {
+    // `contents` has to have any reply fallback stripped already, like a received message's does
+    fn new(sender: Arc<String>, contents: &str) -> ReplyPreview {
+        let line = contents.lines().next().unwrap_or("");
+        let text = if line.chars().count() > 100 {
+            format!("{}…", line.chars().take(100).collect::<String>())
+        } else {
+            String::from(line)
         };
-        f(&current)
+
+        ReplyPreview {
+            sender,
+            text: Arc::new(text),
+        }
     }
 
-    fn with_mut<V, F: FnOnce(&mut CurrentChannel) -> V>(&self, data: &mut Chat, f: F) -> V {
-        let mut current = CurrentChannel {
-            channels_hashed: data.channels_hashed.clone(),
-            current_channel: data.current_channel.clone(),
-        };
-        let v = f(&mut current);
-        data.channels_hashed = current.channels_hashed;
-        data.current_channel = current.current_channel;
-        v
+    fn of(message: &Message) -> ReplyPreview {
+        if message.redacted {
+            ReplyPreview {
+                sender: message.sender.clone(),
+                text: Arc::new(String::from("message deleted")),
+            }
+        } else {
+            ReplyPreview::new(message.sender.clone(), &message.contents)
+        }
     }
 }
 
 #[derive(Data, Clone)]
-struct AllChannels {
-    channels_hashed: HashMap<Arc<String>, Channel>,
-    channels: Vector<Arc<String>>,
-    current_channel: Arc<String>,
+struct Replying {
+    channel: Arc<String>,
+    event_id: Arc<String>,
+    sender: Arc<String>,
+    contents: Arc<String>,
+    html: Option<Arc<String>>,
 }
 
-struct AllChannelsLens;
+impl Message {
+    fn local_echo(channel: Arc<String>, sender: Arc<String>, contents: Arc<String>, reply: Option<ReplyTo>, txs: Senders) -> Message {
+        let html = markdown::markdown_to_html(markdown::parse_markdown(&contents));
+        let formatted = make_rich_text(
+            Some(&Value::from(html.as_str())),
+            Some(&Value::from(contents.as_str())),
+            false,
+        );
+        let reply_preview = reply
+            .as_ref()
+            .map(|v| ReplyPreview::new(v.sender.clone(), chat::strip_reply_fallback(&v.body)));
 
-impl Lens<Chat, AllChannels> for AllChannelsLens {
-    fn with<V, F: FnOnce(&AllChannels) -> V>(&self, data: &Chat, f: F) -> V {
-        let all = AllChannels {
-            channels_hashed: data.channels_hashed.clone(),
-            channels: data.channels.clone(),
-            current_channel: data.current_channel.clone(),
-        };
-        f(&all)
+        Message {
+            event_type: Arc::new(String::from("m.room.message")),
+            edit: None,
+            redacts: None,
+            redacted: false,
+            annotation: None,
+            reactions: Vector::new(),
+            picking_reaction: false,
+            sender: sender.clone(),
+            avatar: AvatarState::Name(sender),
+            event_id: Arc::new(String::new()),
+            transaction_id: Some(chat::new_transaction_id()),
+            send_state: SendState::Sending,
+            contents: contents.clone(),
+            html: Some(Arc::new(html)),
+            formatted,
+            reply_to: reply.as_ref().map(|v| v.event_id.clone()),
+            reply_preview,
+            fetching_reply: false,
+            image: ThumbnailState::None,
+            media_url: None,
+            read_by: Vector::new(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|v| v.as_millis() as u64)
+                .unwrap_or(0),
+            editing_message: contents,
+            editing: false,
+            channel,
+            reply,
+            attachment: None,
+            txs,
+        }
     }
 
-    fn with_mut<V, F: FnOnce(&mut AllChannels) -> V>(&self, data: &mut Chat, f: F) -> V {
-        let mut all = AllChannels {
-            channels_hashed: data.channels_hashed.clone(),
-            channels: data.channels.clone(),
-            current_channel: data.current_channel.clone(),
-        };
-        let v = f(&mut all);
-        data.channels_hashed = all.channels_hashed;
-        data.channels = all.channels;
-        if data.current_channel != all.current_channel {
-            if let Some(channel) = data.channels_hashed.get_mut(&data.current_channel) {
-                channel.bottom = true;
-                channel.top = false;
-                channel.fetching_old = false;
-                if channel.messages.len() > 50 {
-                    channel.messages = channel.messages.skip(channel.messages.len() - 50);
+    fn local_file_echo(channel: Arc<String>, sender: Arc<String>, path: PathBuf, txs: Senders) -> Message {
+        let filename = path
+            .file_name()
+            .map(|v| v.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("file"));
+        let mut message = Message::local_echo(channel, sender, Arc::new(filename), None, txs);
+        message.html = None;
+        message.formatted = make_rich_text(None, Some(&Value::from(message.contents.as_str())), false);
+        message.attachment = Some(Arc::new(path));
+        message
+    }
+
+    fn add_annotation(&mut self, annotation: Annotation, user_id: &str) {
+        let own = annotation.sender.as_str() == user_id;
+        match self.reactions.iter_mut().find(|v| v.key == annotation.key) {
+            Some(reaction) => {
+                if !reaction.annotations.iter().any(|v| v.event_id == annotation.event_id) {
+                    reaction.own |= own;
+                    reaction.annotations.push_back(annotation);
                 }
-                channel.prev_batch = channel.first_batch.clone();
             }
-            data.current_channel = all.current_channel;
+
+            None => self.reactions.push_back(Reaction {
+                channel: self.channel.clone(),
+                target: self.event_id.clone(),
+                key: annotation.key.clone(),
+                annotations: Vector::unit(annotation),
+                own,
+            }),
         }
-        v
     }
-}
 
-impl ListIter<(Arc<String>, Channel)> for AllChannels {
-    fn for_each(&self, mut cb: impl FnMut(&(Arc<String>, Channel), usize)) {
-        for (i, channel) in self.channels.iter().enumerate() {
-            let val = (
-                self.current_channel.clone(),
-                self.channels_hashed.get(channel).unwrap().clone(),
-            );
-            cb(&val, i);
+    fn remove_annotation(&mut self, event_id: &str, user_id: &str) -> bool {
+        let mut removed = false;
+        for reaction in self.reactions.iter_mut() {
+            let count = reaction.annotations.len();
+            reaction.annotations.retain(|v| v.event_id.as_str() != event_id);
+            if count != reaction.annotations.len() {
+                reaction.own = reaction.annotations.iter().any(|v| v.sender.as_str() == user_id);
+                removed = true;
+            }
         }
+
+        self.reactions.retain(|v| !v.annotations.is_empty());
+        removed
     }
 
-    fn for_each_mut(&mut self, mut cb: impl FnMut(&mut (Arc<String>, Channel), usize)) {
-        for (i, channel) in self.channels.iter().enumerate() {
-            let mut val = (
-                self.current_channel.clone(),
-                self.channels_hashed.get(channel).unwrap().clone(),
-            );
-            cb(&mut val, i);
-            self.current_channel = val.0;
-            *self.channels_hashed.get_mut(channel).unwrap() = val.1;
-        }
+    fn redact(&mut self) {
+        let mut builder = RichTextBuilder::new();
+        builder.push("message deleted")
+            .add_attr(Attribute::text_color(Color::GRAY))
+            .add_attr(Attribute::Style(FontStyle::Italic));
+
+        self.redacted = true;
+        self.edit = None;
+        self.contents = Arc::new(String::new());
+        self.formatted = builder.build();
+        self.image = ThumbnailState::None;
+        self.reactions = Vector::new();
+        self.editing = false;
     }
 
-    fn data_len(&self) -> usize {
-        self.channels.len()
+    fn send(&self) {
+        let txn_id = match &self.transaction_id {
+            Some(v) => v.clone(),
+            None => return,
+        };
+
+        let action = if let Some(path) = &self.attachment {
+            UserAction::SendFile(self.channel.clone(), txn_id, path.clone())
+        } else {
+            let formatted = markdown::markdown_to_html(markdown::parse_markdown(&self.contents));
+            UserAction::SendMessage(
+                self.channel.clone(),
+                txn_id,
+                self.contents.clone(),
+                Arc::new(formatted),
+                self.reply.clone(),
+            )
+        };
+
+        match self.txs.action_tx.try_send(action) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+            Err(TrySendError::Closed(_)) => panic!("oh no"),
+        }
     }
 }
 
-fn extract_text_and_text_attributes_from_dom(
-    node: NodeRef,
-    builder: &mut RichTextBuilder,
-    current_pos: &mut usize,
-) {
-    match node.data() {
-        NodeData::Text(t) => {
-            let t = t.borrow();
-            builder.push(&*t);
-            *current_pos += t.len();
+#[derive(Data, Clone, Lens)]
+struct Login {
+    user: Arc<String>,
+    password: Arc<String>,
+    homeserver: Arc<String>,
+    error: Arc<String>,
+    logging_in: bool,
+
+    #[data(ignore)]
+    txs: Senders,
+}
+
+impl Login {
+    fn submit(&mut self) {
+        if self.logging_in || self.user.is_empty() || self.password.is_empty() {
+            return;
         }
 
-        NodeData::Element(e) => {
-            let start = *current_pos;
-            for child in node.children() {
-                extract_text_and_text_attributes_from_dom(child, builder, current_pos);
-            }
-            let end = *current_pos;
-            if e.name.local.as_ref().starts_with('h') || e.name.local.as_ref() == "br" {
-                builder.push("\n");
-                *current_pos += 1;
-            }
+        let homeserver = if !self.homeserver.is_empty() {
+            self.homeserver.clone()
+        } else if let Some((_, server)) = self.user.strip_prefix('@').and_then(|v| v.split_once(':')) {
+            Arc::new(String::from(server))
+        } else {
+            self.error = Arc::new(String::from("a homeserver is required when logging in with a username"));
+            return;
+        };
 
-            let text_size = 16.0;
-            match e.name.local.as_ref() {
-                "em" => {
-                    builder.add_attributes_for_range(start..end)
-                        .add_attr(Attribute::Style(FontStyle::Italic));
-                }
+        match self.txs.action_tx.try_send(UserAction::Login(
+            homeserver,
+            self.user.clone(),
+            self.password.clone(),
+        )) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+            Err(TrySendError::Closed(_)) => panic!("oh no"),
+        }
+        self.error = Arc::new(String::new());
+        self.logging_in = true;
+    }
+}
 
-                "strong" => {
-                    builder.add_attributes_for_range(start..end)
-                        .add_attr(Attribute::Weight(FontWeight::new(700)));
-                }
+const CONNECTION_COLOR: druid::Key<Color> = druid::Key::new("uwutalk.connection_color");
+
+#[derive(Data, Clone, Copy, PartialEq)]
+enum Connection {
+    Connected,
+    Reconnecting,
+    Offline,
+}
+
+#[derive(Data, Clone, Lens)]
+pub struct Chat {
+    logged_in: bool,
+    user_id: Arc<String>,
+    login: Login,
+    editing_message: Arc<String>,
+    replying_to: Option<Replying>,
+    viewers: HashMap<Arc<String>, Viewer>,
+    sort: SortMode,
+    channel_filter: Arc<String>,
+    switcher: Switcher,
+    prompt: Option<Prompt>,
+    prompt_text: Arc<String>,
+    direct: HashMap<Arc<String>, Arc<String>>,
+    invites: Vector<Invite>,
+    new_room: Option<NewRoom>,
+    notice: Option<Arc<String>>,
+    settings: Option<RoomSettings>,
+    next_batch: Arc<String>,
+    connection: Connection,
+    sync_failures: u32,
+    selected_space: Option<Arc<String>>,
+    space_children: HashMap<Arc<String>, Vector<Arc<String>>>,
+    channels_hashed: HashMap<Arc<String>, Channel>,
+    channels: Vector<Arc<String>>,
+    current_channel: Arc<String>,
+
+    #[data(ignore)]
+    scroll: Option<f64>,
+
+    #[data(ignore)]
+    last_input: Option<Instant>,
+
+    #[data(ignore)]
+    retry_timer: TimerToken,
+
+    #[data(ignore)]
+    session: u64,
+
+    #[data(ignore)]
+    txs: Senders,
+}
+
+impl Chat {
+    pub fn new(sync_tx: mpsc::Sender<Syncing>, action_tx: mpsc::Sender<UserAction>, media_tx: mpsc::Sender<MediaFetch>, user_id: Option<Arc<String>>) -> Chat {
+        let txs = Senders {
+            sync_tx,
+            action_tx,
+            media_tx,
+        };
+
+        Chat {
+            logged_in: user_id.is_some(),
+            user_id: user_id.unwrap_or_default(),
+            login: Login {
+                user: Arc::new(String::new()),
+                password: Arc::new(String::new()),
+                homeserver: Arc::new(String::new()),
+                error: Arc::new(String::new()),
+                logging_in: false,
+                txs: txs.clone(),
+            },
+            editing_message: Arc::new(String::new()),
+            replying_to: None,
+            viewers: HashMap::new(),
+            sort: SortMode::Recent,
+            channel_filter: Arc::new(String::new()),
+            switcher: Switcher {
+                open: false,
+                query: Arc::new(String::new()),
+                selected: 0,
+            },
+            prompt: None,
+            prompt_text: Arc::new(String::new()),
+            direct: HashMap::new(),
+            invites: Vector::new(),
+            new_room: None,
+            notice: None,
+            settings: None,
+            next_batch: Arc::new(String::new()),
+            connection: Connection::Connected,
+            sync_failures: 0,
+            retry_timer: TimerToken::INVALID,
+            session: 0,
+            selected_space: None,
+            space_children: HashMap::new(),
+            channels_hashed: HashMap::new(),
+            channels: Vector::new(),
+            current_channel: Arc::new(String::new()),
+            scroll: None,
+            last_input: None,
+            txs,
+        }
+    }
+
+    fn log_out(&mut self) {
+        match self.txs.action_tx.try_send(UserAction::Logout) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+            Err(TrySendError::Closed(_)) => panic!("oh no"),
+        }
+
+        self.logged_in = false;
+        self.user_id = Arc::new(String::new());
+        self.channels_hashed = HashMap::new();
+        self.channels = Vector::new();
+        self.invites = Vector::new();
+        self.direct = HashMap::new();
+        self.space_children = HashMap::new();
+        self.selected_space = None;
+        self.current_channel = Arc::new(String::new());
+        self.replying_to = None;
+        self.notice = None;
+        self.settings = None;
+        self.new_room = None;
+        self.prompt = None;
+        self.next_batch = Arc::new(String::new());
+        self.connection = Connection::Connected;
+        self.sync_failures = 0;
+        self.retry_timer = TimerToken::INVALID;
+        self.session += 1;
+    }
+
+    fn select_channel(&mut self, id: Arc<String>) {
+        if self.current_channel == id {
+            return;
+        }
+
+        if let Some(channel) = self.channels_hashed.get_mut(&self.current_channel) {
+            channel.bottom = true;
+            channel.top = false;
+            channel.fetching_old = false;
+            if channel.messages.len() > 50 {
+                channel.messages = channel.messages.skip(channel.messages.len() - 50);
+            }
+            channel.prev_batch = channel.first_batch.clone();
+        }
+        self.current_channel = id;
+        self.replying_to = None;
+    }
+
+    fn visible_channels(&self) -> Vector<Arc<String>> {
+        let filter = self.channel_filter.to_lowercase();
+        let space = self.selected_space.as_ref().map(|v| self.space_children.get(v).cloned().unwrap_or_default());
+        let mut channels: Vec<&Channel> = self
+            .channels
+            .iter()
+            .filter_map(|id| self.channels_hashed.get(id))
+            .filter(|v| !v.is_space)
+            .filter(|v| match &space {
+                Some(children) => children.contains(&v.id),
+                None => true,
+            })
+            .filter(|v| filter.is_empty() || v.display_name().to_lowercase().contains(&filter) || v.aliases.iter().any(|v| v.to_lowercase().contains(&filter)))
+            .collect();
+
+        match self.sort {
+            SortMode::Recent => channels.sort_by(|a, b| b.last_activity.cmp(&a.last_activity)),
+            SortMode::Alphabetical => channels.sort_by_key(|v| v.display_name().to_lowercase()),
+            SortMode::Unread => channels.sort_by(|a, b| {
+                (b.highlight_count > 0, b.notification_count > 0, b.last_activity)
+                    .cmp(&(a.highlight_count > 0, a.notification_count > 0, a.last_activity))
+            }),
+        }
+
+        channels.into_iter().map(|v| v.id.clone()).collect()
+    }
+
+    fn channel_sections(&self) -> Vector<ChannelSection> {
+        let visible = self.visible_channels();
+        let mut custom: Vec<Arc<String>> = visible
+            .iter()
+            .filter_map(|id| self.channels_hashed.get(id))
+            .flat_map(|v| v.tags.keys().filter(|v| v.starts_with("u.")).cloned())
+            .collect();
+        custom.sort();
+        custom.dedup();
+
+        let section = |title: &str, tag: Option<&str>| {
+            let mut channels: Vec<&Channel> = visible
+                .iter()
+                .filter_map(|id| self.channels_hashed.get(id))
+                .filter(|v| match tag {
+                    Some(PEOPLE) => v.dm.is_some() && !v.tags.keys().any(|v| is_section_tag(v)),
+                    Some(tag) => v.has_tag(tag),
+                    None => v.dm.is_none() && !v.tags.keys().any(|v| is_section_tag(v)),
+                })
+                .collect();
+
+            // tagged rooms go by their order first, and rooms without one go last
+            if let Some(tag) = tag {
+                channels.sort_by(|a, b| match (a.tag_order(tag), b.tag_order(tag)) {
+                    (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal),
+                    (Some(_), None) => std::cmp::Ordering::Less,
+                    (None, Some(_)) => std::cmp::Ordering::Greater,
+                    (None, None) => std::cmp::Ordering::Equal,
+                });
+            }
+
+            ChannelSection {
+                title: Arc::new(String::from(title)),
+                channels_hashed: self.channels_hashed.clone(),
+                channels: channels.into_iter().map(|v| v.id.clone()).collect(),
+                current_channel: self.current_channel.clone(),
+            }
+        };
+
+        let mut sections = vec![
+            section("Favourites", Some(FAVOURITE)),
+            section("People", Some(PEOPLE)),
+        ];
+        for tag in custom.iter() {
+            sections.push(section(&tag[2..], Some(tag)));
+        }
+        sections.push(section("Rooms", None));
+        sections.push(section("Low priority", Some(LOW_PRIORITY)));
+        sections.into_iter().filter(|v| !v.channels.is_empty()).collect()
+    }
+
+    fn apply_direct(&mut self) {
+        let mut fetch = vec![];
+        for (id, channel) in self.channels_hashed.iter_mut() {
+            let user = self.direct.get(id);
+            if channel.dm.as_ref().map(|v| &v.user_id) == user {
+                continue;
+            }
+
+            channel.dm = user.map(|user| DirectInfo {
+                user_id: user.clone(),
+                display_name: None,
+                avatar: AvatarState::Name(user.clone()),
+                txs: self.txs.clone(),
+            });
+
+            if let Some(user) = user {
+                fetch.push(user.clone());
+            }
+        }
+
+        if !fetch.is_empty() {
+            match self.txs.sync_tx.try_send(Syncing::FetchDisplayNames(fetch)) {
+                Ok(_) => (),
+                Err(TrySendError::Full(_)) => panic!("oh no"),
+                Err(TrySendError::Closed(_)) => panic!("aaaaa"),
+            }
+        }
+    }
+
+    fn start_dm(&mut self, user: Arc<String>) {
+        let existing = self
+            .direct
+            .iter()
+            .find(|(room, v)| **v == user && self.channels_hashed.contains_key(*room))
+            .map(|(room, _)| room.clone());
+
+        match existing {
+            Some(room) => self.select_channel(room),
+            None => match self.txs.action_tx.try_send(UserAction::CreateDirect(user)) {
+                Ok(_) => (),
+                Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+                Err(TrySendError::Closed(_)) => panic!("oh no"),
+            },
+        }
+    }
+
+    fn set_tag(&mut self, room: &Arc<String>, tag: &str, tagged: bool) {
+        let channel = match self.channels_hashed.get_mut(room) {
+            Some(v) => v,
+            None => return,
+        };
+        if channel.has_tag(tag) == tagged {
+            return;
+        }
+
+        let tag = Arc::new(String::from(tag));
+        let action = if tagged {
+            channel.tags.insert(tag.clone(), None);
+            UserAction::SetTag(room.clone(), tag)
+        } else {
+            channel.tags.remove(&tag);
+            UserAction::RemoveTag(room.clone(), tag)
+        };
+
+        match self.txs.action_tx.try_send(action) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+            Err(TrySendError::Closed(_)) => panic!("oh no"),
+        }
+    }
+
+    fn rail_spaces(&self) -> Vector<SpaceEntry> {
+        // subspaces are reached through their parents, so only list the top level
+        self.channels
+            .iter()
+            .filter_map(|id| self.channels_hashed.get(id))
+            .filter(|v| v.is_space)
+            .filter(|v| !self.space_children.iter().any(|(parent, children)| *parent != v.id && children.contains(&v.id)))
+            .map(|v| SpaceEntry {
+                id: v.id.clone(),
+                name: v.name.clone(),
+                selected: self.selected_space.as_ref() == Some(&v.id),
+            })
+            .collect()
+    }
+
+    fn fetch_hierarchy(&self, spaces: Vec<Arc<String>>) {
+        match self.txs.sync_tx.try_send(Syncing::FetchHierarchy(spaces)) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => panic!("oh no"),
+            Err(TrySendError::Closed(_)) => panic!("aaaaa"),
+        }
+    }
+
+    fn join(&self, room: Arc<String>, via: Vec<Arc<String>>) {
+        match self.txs.action_tx.try_send(UserAction::JoinRoom(room, via)) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+            Err(TrySendError::Closed(_)) => panic!("oh no"),
+        }
+    }
+
+    fn leave(&self, room: Arc<String>) {
+        match self.txs.action_tx.try_send(UserAction::LeaveRoom(room)) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+            Err(TrySendError::Closed(_)) => panic!("oh no"),
+        }
+    }
+
+    fn can_moderate(&self, room: &Arc<String>, action: Moderation, target: &str) -> bool {
+        match self.channels_hashed.get(room) {
+            Some(channel) => action.allowed(&channel.power_levels, &self.user_id, target),
+            None => false,
+        }
+    }
+
+    fn moderate(&self, room: Arc<String>, action: Moderation, target: Arc<String>, reason: Option<Arc<String>>) -> Result<(), String> {
+        if !self.can_moderate(&room, action, &target) {
+            return Err(format!("you aren't allowed to {} {} here", action.command(), target));
+        }
+
+        match self.txs.action_tx.try_send(UserAction::Moderate(room, action, target, reason)) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+            Err(TrySendError::Closed(_)) => panic!("oh no"),
+        }
+    }
+
+    fn run_command(&self, line: &str) -> Result<(), String> {
+        let mut parts = line[1..].splitn(3, ' ');
+        let command = parts.next().unwrap_or("");
+        let action = Moderation::from_command(command).ok_or_else(|| format!("unknown command /{}", command))?;
+        let target = match parts.next() {
+            Some(v) if v.starts_with('@') && v.contains(':') => Arc::new(String::from(v)),
+            _ => return Err(format!("usage: /{} @user:server [reason]", command)),
+        };
+        let reason = parts.next().map(str::trim).filter(|v| !v.is_empty()).map(|v| Arc::new(String::from(v)));
+
+        self.moderate(self.current_channel.clone(), action, target, reason)
+    }
+
+    fn open_settings(&mut self, room: Arc<String>) {
+        let levels = match self.channels_hashed.get(&room) {
+            Some(channel) => channel.power_levels.clone(),
+            None => return,
+        };
+
+        match self.txs.sync_tx.try_send(Syncing::FetchRoomState(room.clone())) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => panic!("oh no"),
+            Err(TrySendError::Closed(_)) => panic!("aaaaa"),
+        }
+        self.new_room = None;
+        self.settings = Some(RoomSettings::new(room, self.user_id.clone(), levels));
+    }
+
+    fn remove_channel(&mut self, room: &Arc<String>) {
+        self.invites.retain(|v| &v.room_id != room);
+        self.channels_hashed.remove(room);
+        self.channels.retain(|v| v != room);
+        self.space_children.remove(room);
+        self.direct.remove(room);
+        if &self.current_channel == room {
+            self.current_channel = Arc::new(String::new());
+            self.replying_to = None;
+        }
+        if self.selected_space.as_ref() == Some(room) {
+            self.selected_space = None;
+        }
+    }
+
+    fn switcher_results(&self) -> Vector<SwitcherItem> {
+        let query = self.switcher.query.to_lowercase();
+        let mut results: Vec<(i64, &Channel)> = self
+            .channels
+            .iter()
+            .filter_map(|id| self.channels_hashed.get(id))
+            .filter_map(|channel| {
+                std::iter::once(channel.display_name())
+                    .chain(channel.aliases.iter().cloned())
+                    .filter_map(|v| fuzzy_score(&query, &v.to_lowercase()))
+                    .max()
+                    .map(|score| (score, channel))
+            })
+            .collect();
+        results.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.last_activity.cmp(&a.1.last_activity)));
+
+        results
+            .into_iter()
+            .take(SWITCHER_RESULTS)
+            .enumerate()
+            .map(|(i, (_, channel))| SwitcherItem {
+                id: channel.id.clone(),
+                name: channel.display_name(),
+                alias: channel.aliases.front().cloned(),
+                selected: i == self.switcher.selected,
+            })
+            .collect()
+    }
+
+    // picks up from the last successful sync, or starts over if there wasn't one
+    fn sync(&self) {
+        let filter = if self.next_batch.is_empty() {
+            initial_filter()
+        } else {
+            sync_filter()
+        };
+
+        match self.txs.sync_tx.try_send(Syncing::ClientSync(self.session, self.next_batch.clone(), filter)) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+            Err(TrySendError::Closed(_)) => panic!("oh no"),
+        }
+    }
+}
+
+const MESSAGE_TYPES: [&str; 3] = ["m.room.message", "m.room.redaction", "m.reaction"];
+
+fn initial_filter() -> chat::Filter {
+    chat::Filter::new()
+        .presence(chat::EventFilter::new().limit(0))
+        .room(
+            chat::RoomFilter::new()
+                .ephemeral(chat::RoomEventFilter::new().types(&["m.typing", "m.receipt"]))
+                .state(
+                    chat::RoomEventFilter::new()
+                        .types(&[
+                            "m.room.name",
+                            "m.room.canonical_alias",
+                            "m.room.member",
+                            "m.room.create",
+                            "m.space.child",
+                            "m.room.power_levels",
+                        ])
+                        .lazy_load_members(true),
+                )
+                // just the newest message, so sorting by recent activity has something to go on
+                .timeline(chat::RoomEventFilter::new().limit(1).types(&MESSAGE_TYPES)),
+        )
+}
+
+// state changes we care about come through the timeline once we're caught up
+fn sync_filter() -> chat::Filter {
+    let types: Vec<&str> = MESSAGE_TYPES
+        .iter()
+        .chain(["m.room.name", "m.room.canonical_alias", "m.room.member", "m.room.power_levels", "m.space.child"].iter())
+        .copied()
+        .collect();
+
+    chat::Filter::new().room(
+        chat::RoomFilter::new()
+            .ephemeral(chat::RoomEventFilter::new().types(&["m.typing", "m.receipt"]))
+            .state(chat::RoomEventFilter::new().lazy_load_members(true))
+            .timeline(chat::RoomEventFilter::new().limit(50).types(&types)),
+    )
+}
+
+fn server_name(id: &str) -> Option<&str> {
+    id.split_once(':').map(|(_, v)| v)
+}
+
+fn sync_backoff(failures: u32) -> Duration {
+    (SYNC_RETRY_MIN * 2u32.saturating_pow(failures.min(16))).min(SYNC_RETRY_MAX)
+}
+
+struct CurrentChannel {
+    channels_hashed: HashMap<Arc<String>, Channel>,
+    current_channel: Arc<String>,
+}
+
+struct CurrentChannelLens;
+
+impl Lens<Chat, CurrentChannel> for CurrentChannelLens {
+    fn with<V, F: FnOnce(&CurrentChannel) -> V>(&self, data: &Chat, f: F) -> V {
+        let current = CurrentChannel {
+            channels_hashed: data.channels_hashed.clone(),
+            current_channel: data.current_channel.clone(),
+        };
+        f(&current)
+    }
+
+    fn with_mut<V, F: FnOnce(&mut CurrentChannel) -> V>(&self, data: &mut Chat, f: F) -> V {
+        let mut current = CurrentChannel {
+            channels_hashed: data.channels_hashed.clone(),
+            current_channel: data.current_channel.clone(),
+        };
+        let v = f(&mut current);
+        data.channels_hashed = current.channels_hashed;
+        data.current_channel = current.current_channel;
+        v
+    }
+}
+
+#[derive(Data, Clone, Lens)]
+struct Viewer {
+    url: Arc<String>,
+    filename: Arc<String>,
+    progress: f64,
+    image: Option<Arc<ImageBuf>>,
+    path: Option<Arc<PathBuf>>,
+    error: Option<Arc<String>>,
+
+    // the same image can be open in a few windows, and it stays loaded until they're all closed
+    windows: usize,
+}
+
+impl Viewer {
+    fn new(url: Arc<String>, filename: Arc<String>) -> Viewer {
+        Viewer {
+            url,
+            filename,
+            progress: 0.0,
+            image: None,
+            path: None,
+            error: None,
+            windows: 0,
+        }
+    }
+}
+
+struct ViewerLens(Arc<String>);
+
+impl Lens<Chat, Viewer> for ViewerLens {
+    fn with<V, F: FnOnce(&Viewer) -> V>(&self, data: &Chat, f: F) -> V {
+        match data.viewers.get(&self.0) {
+            Some(v) => f(v),
+            None => f(&Viewer::new(self.0.clone(), Arc::new(String::new()))),
+        }
+    }
+
+    fn with_mut<V, F: FnOnce(&mut Viewer) -> V>(&self, data: &mut Chat, f: F) -> V {
+        match data.viewers.get_mut(&self.0) {
+            Some(v) => f(v),
+            None => f(&mut Viewer::new(self.0.clone(), Arc::new(String::new()))),
+        }
+    }
+}
+
+#[derive(Data, Clone, Copy, PartialEq)]
+enum SortMode {
+    Recent,
+    Alphabetical,
+    Unread,
+}
+
+#[derive(Data, Clone, Lens)]
+struct Switcher {
+    open: bool,
+    query: Arc<String>,
+    selected: usize,
+}
+
+#[derive(Data, Clone)]
+struct SpaceEntry {
+    id: Arc<String>,
+    name: Arc<String>,
+    selected: bool,
+}
+
+#[derive(Data, Clone)]
+struct Invite {
+    room_id: Arc<String>,
+    name: Arc<String>,
+    inviter: Arc<String>,
+    via: Option<Arc<String>>,
+    is_direct: bool,
+    pending: bool,
+}
+
+impl Invite {
+    fn new(room_id: Arc<String>, room: &chat::InvitedRoom, user_id: &str) -> Invite {
+        let events = &room.invite_state.events;
+        let get = |type_: &str, state_key: &str, key: &str| {
+            events
+                .iter()
+                .find(|v| v.type_.as_str() == type_ && v.state_key.as_str() == state_key)
+                .and_then(|v| v.content.get(key))
+                .and_then(Value::as_string)
+                .map(|v| Arc::new(String::from(v.as_str())))
+        };
+
+        let own = events
+            .iter()
+            .find(|v| v.type_.as_str() == "m.room.member" && v.state_key.as_str() == user_id);
+        let sender = own.map(|v| v.sender.clone()).unwrap_or_default();
+        let is_direct = own
+            .and_then(|v| v.content.get("is_direct"))
+            .and_then(Value::to_bool)
+            .unwrap_or(false);
+        let inviter = get("m.room.member", sender.as_str(), "displayname").unwrap_or_else(|| sender.clone());
+        // whoever invited us is in the room, so their server can always get us in
+        let via = server_name(&sender).map(|v| Arc::new(String::from(v)));
+
+        let name = get("m.room.name", "", "name")
+            .or_else(|| get("m.room.canonical_alias", "", "alias"))
+            .unwrap_or_else(|| if is_direct { inviter.clone() } else { room_id.clone() });
+
+        Invite {
+            room_id,
+            name,
+            inviter,
+            via,
+            is_direct,
+            pending: false,
+        }
+    }
+}
+
+impl Data for chat::RoomPreset {
+    fn same(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+#[derive(Data, Clone, Lens)]
+struct NewRoom {
+    name: Arc<String>,
+    topic: Arc<String>,
+    alias: Arc<String>,
+    preset: chat::RoomPreset,
+    invite: Arc<String>,
+    encrypted: bool,
+    space: bool,
+    creating: bool,
+    error: Option<Arc<String>>,
+}
+
+impl NewRoom {
+    fn new() -> NewRoom {
+        NewRoom {
+            name: Arc::new(String::new()),
+            topic: Arc::new(String::new()),
+            alias: Arc::new(String::new()),
+            preset: chat::RoomPreset::Private,
+            invite: Arc::new(String::new()),
+            encrypted: false,
+            space: false,
+            creating: false,
+            error: None,
+        }
+    }
+
+    fn options(&self) -> chat::CreateRoom {
+        let field = |v: &Arc<String>| Some(String::from(v.trim())).filter(|v| !v.is_empty());
+
+        chat::CreateRoom {
+            name: field(&self.name),
+            topic: field(&self.topic),
+            alias: field(&self.alias).map(|v| String::from(v.trim_start_matches('#'))),
+            preset: Some(self.preset),
+            invite: self
+                .invite
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|v| !v.is_empty())
+                .map(String::from)
+                .collect(),
+            is_direct: false,
+            encrypted: self.encrypted,
+            room_type: if self.space { Some(String::from("m.space")) } else { None },
+        }
+    }
+}
+
+pub const SETTINGS_STATE: [&str; 7] = [
+    "m.room.name",
+    "m.room.topic",
+    "m.room.avatar",
+    "m.room.join_rules",
+    "m.room.history_visibility",
+    "m.room.guest_access",
+    "m.room.power_levels",
+];
+
+const JOIN_RULES: [&str; 3] = ["invite", "knock", "public"];
+const HISTORY_VISIBILITY: [&str; 4] = ["shared", "invited", "joined", "world_readable"];
+const GUEST_ACCESS: [&str; 2] = ["forbidden", "can_join"];
+
+fn next_option(options: &[&str], current: &str) -> Arc<String> {
+    let i = options.iter().position(|v| *v == current).map(|v| v + 1).unwrap_or(0);
+    Arc::new(String::from(options[i % options.len()]))
+}
+
+#[derive(Data, Clone, Lens)]
+struct LevelEntry {
+    key: Arc<String>,
+    level: Arc<String>,
+}
+
+#[derive(Data, Clone, Lens)]
+struct RoomSettings {
+    room: Arc<String>,
+    user_id: Arc<String>,
+    loading: bool,
+    error: Option<Arc<String>>,
+    name: Arc<String>,
+    topic: Arc<String>,
+    avatar: Arc<String>,
+    join_rule: Arc<String>,
+    history_visibility: Arc<String>,
+    guest_access: Arc<String>,
+    users: Vector<LevelEntry>,
+    events: Vector<LevelEntry>,
+    levels: Arc<chat::PowerLevels>,
+
+    #[data(ignore)]
+    original: HashMap<String, Value>,
+}
+
+impl RoomSettings {
+    fn new(room: Arc<String>, user_id: Arc<String>, levels: Arc<chat::PowerLevels>) -> RoomSettings {
+        RoomSettings {
+            room,
+            user_id,
+            loading: true,
+            error: None,
+            name: Arc::new(String::new()),
+            topic: Arc::new(String::new()),
+            avatar: Arc::new(String::new()),
+            join_rule: Arc::new(String::new()),
+            history_visibility: Arc::new(String::new()),
+            guest_access: Arc::new(String::new()),
+            users: Vector::new(),
+            events: Vector::new(),
+            levels,
+            original: HashMap::new(),
+        }
+    }
+
+    fn original(&self, event_type: &str, key: &str, default: &str) -> Arc<String> {
+        let value = self
+            .original
+            .get(event_type)
+            .and_then(|v| v.get(key))
+            .and_then(Value::as_string)
+            .map(IString::as_str)
+            .unwrap_or(default);
+        Arc::new(String::from(value))
+    }
+
+    fn load(&mut self, state: &[(String, Value)]) {
+        self.original = state.iter().cloned().collect();
+        self.name = self.original("m.room.name", "name", "");
+        self.topic = self.original("m.room.topic", "topic", "");
+        self.avatar = self.original("m.room.avatar", "url", "");
+        self.join_rule = self.original("m.room.join_rules", "join_rule", "invite");
+        self.history_visibility = self.original("m.room.history_visibility", "history_visibility", "shared");
+        self.guest_access = self.original("m.room.guest_access", "guest_access", "forbidden");
+
+        if let Some(levels) = self.original.get("m.room.power_levels") {
+            // editing from empty lists would write back only what was added and demote everyone else
+            match ijson::from_value(levels) {
+                Ok(levels) => self.levels = Arc::new(levels),
+                Err(e) => {
+                    self.error = Some(Arc::new(format!("can't edit this room's power levels: {}", e)));
+                    return;
+                }
+            }
+        }
+
+        let entries = |map: &std::collections::HashMap<String, i64>| {
+            let mut entries: Vec<LevelEntry> = map
+                .iter()
+                .map(|(k, v)| LevelEntry {
+                    key: Arc::new(k.clone()),
+                    level: Arc::new(v.to_string()),
+                })
+                .collect();
+            entries.sort_by(|a, b| a.key.cmp(&b.key));
+            entries.into_iter().collect()
+        };
+        self.users = entries(&self.levels.users);
+        self.events = entries(&self.levels.events);
+        self.loading = false;
+    }
+
+    fn can(&self, event_type: &str) -> bool {
+        !self.loading && self.levels.user_level(&self.user_id) >= self.levels.state_level(event_type)
+    }
+
+    fn parse_levels(entries: &Vector<LevelEntry>) -> Result<std::collections::HashMap<String, i64>, String> {
+        // a blank level means the entry should go back to the room's default
+        entries
+            .iter()
+            .filter(|v| !v.key.trim().is_empty() && !v.level.trim().is_empty())
+            .map(|v| match v.level.trim().parse() {
+                Ok(level) => Ok((String::from(v.key.trim()), level)),
+                Err(_) => Err(format!("\"{}\" isn't a valid level for {}", v.level, v.key)),
+            })
+            .collect()
+    }
+
+    // what an event type falls back to without an entry, most of what ends up listed is state
+    fn event_default(&self, event_type: &str) -> i64 {
+        if MESSAGE_TYPES.contains(&event_type) || ["m.room.encrypted", "m.sticker"].contains(&event_type) {
+            self.levels.events_default
+        } else {
+            self.levels.state_default
+        }
+    }
+
+    fn check_levels(
+        &self,
+        old: &std::collections::HashMap<String, i64>,
+        new: &std::collections::HashMap<String, i64>,
+        default: impl Fn(&str) -> i64,
+        is_user: bool,
+    ) -> Result<(), String> {
+        let own = self.levels.user_level(&self.user_id);
+        for key in old.keys().chain(new.keys()) {
+            let before = old.get(key).copied().unwrap_or_else(|| default(key.as_str()));
+            let after = new.get(key).copied().unwrap_or_else(|| default(key.as_str()));
+            if before == after {
+                continue;
+            }
+
+            if after > own {
+                return Err(format!("you can't set {} higher than your own level ({})", key, own));
+            }
+            if before > own || (is_user && before == own && key != self.user_id.as_str()) {
+                return Err(format!("you can't change {}, their level isn't below yours", key));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn changes(&self) -> Result<Vec<(&'static str, String)>, String> {
+        let mut changes = vec![];
+        let fields = [
+            ("m.room.name", "name", "", &self.name),
+            ("m.room.topic", "topic", "", &self.topic),
+            ("m.room.avatar", "url", "", &self.avatar),
+            ("m.room.join_rules", "join_rule", "invite", &self.join_rule),
+            ("m.room.history_visibility", "history_visibility", "shared", &self.history_visibility),
+            ("m.room.guest_access", "guest_access", "forbidden", &self.guest_access),
+        ];
+
+        for &(event_type, key, default, value) in fields.iter() {
+            if self.original(event_type, key, default) != *value {
+                changes.push((event_type, json!({ key: value.as_str() }).to_string()));
+            }
+        }
+
+        let users = RoomSettings::parse_levels(&self.users)?;
+        let events = RoomSettings::parse_levels(&self.events)?;
+        if users != self.levels.users || events != self.levels.events {
+            self.check_levels(&self.levels.users, &users, |_| self.levels.users_default, true)?;
+            self.check_levels(&self.levels.events, &events, |v| self.event_default(v), false)?;
+
+            // keep everything we don't edit here, like notification levels
+            let mut content = self
+                .original
+                .get("m.room.power_levels")
+                .and_then(|v| serde_json::to_value(v).ok())
+                .unwrap_or_else(|| json!({}));
+            content["users"] = json!(users);
+            content["events"] = json!(events);
+            changes.push(("m.room.power_levels", content.to_string()));
+        }
+
+        Ok(changes)
+    }
+}
+
+#[derive(Data, Clone)]
+struct SwitcherItem {
+    id: Arc<String>,
+    name: Arc<String>,
+    alias: Option<Arc<String>>,
+    selected: bool,
+}
+
+const SWITCHER_RESULTS: usize = 10;
+
+// characters of `query` have to show up in order, and runs of them or matches at the start of words score higher
+fn fuzzy_score(query: &str, target: &str) -> Option<i64> {
+    if query.is_empty() {
+        return Some(0);
+    }
+
+    let mut score = 0;
+    let mut run = 0;
+    let mut previous = None;
+    let mut chars = target.chars();
+    for q in query.chars() {
+        loop {
+            let c = chars.next()?;
+            let word_start = matches!(previous, None | Some(' ') | Some('-') | Some('_') | Some('#') | Some(':'));
+            previous = Some(c);
+            if c == q {
+                run += 1;
+                score += run * 2 + if word_start { 3 } else { 0 };
+                break;
+            }
+            run = 0;
+        }
+    }
+
+    // prefer shorter names when everything else is equal
+    Some(score * 100 - target.len() as i64)
+}
+
+const FAVOURITE: &str = "m.favourite";
+const LOW_PRIORITY: &str = "m.lowpriority";
+
+// not a real tag, direct messages get their own section
+const PEOPLE: &str = "uwutalk.people";
+
+fn is_section_tag(tag: &str) -> bool {
+    tag == FAVOURITE || tag == LOW_PRIORITY || tag.starts_with("u.")
+}
+
+#[derive(Data, Clone)]
+struct ChannelSection {
+    title: Arc<String>,
+    channels_hashed: HashMap<Arc<String>, Channel>,
+    channels: Vector<Arc<String>>,
+    current_channel: Arc<String>,
+}
+
+struct ChannelSectionsLens;
+
+impl Lens<Chat, Vector<ChannelSection>> for ChannelSectionsLens {
+    fn with<V, F: FnOnce(&Vector<ChannelSection>) -> V>(&self, data: &Chat, f: F) -> V {
+        f(&data.channel_sections())
+    }
+
+    fn with_mut<V, F: FnOnce(&mut Vector<ChannelSection>) -> V>(&self, data: &mut Chat, f: F) -> V {
+        let mut sections = data.channel_sections();
+        let v = f(&mut sections);
+        for section in sections.iter() {
+            for id in section.channels.iter() {
+                if let (Some(new), Some(old)) = (section.channels_hashed.get(id), data.channels_hashed.get_mut(id)) {
+                    if !old.same(new) {
+                        *old = new.clone();
+                    }
+                }
+            }
+        }
+        if let Some(section) = sections.iter().find(|v| v.current_channel != data.current_channel) {
+            data.select_channel(section.current_channel.clone());
+        }
+        v
+    }
+}
+
+impl ListIter<(Arc<String>, Channel)> for ChannelSection {
+    fn for_each(&self, mut cb: impl FnMut(&(Arc<String>, Channel), usize)) {
+        for (i, channel) in self.channels.iter().enumerate() {
+            let val = (
+                self.current_channel.clone(),
+                self.channels_hashed.get(channel).unwrap().clone(),
+            );
+            cb(&val, i);
+        }
+    }
+
+    fn for_each_mut(&mut self, mut cb: impl FnMut(&mut (Arc<String>, Channel), usize)) {
+        for (i, channel) in self.channels.iter().enumerate() {
+            let mut val = (
+                self.current_channel.clone(),
+                self.channels_hashed.get(channel).unwrap().clone(),
+            );
+            cb(&mut val, i);
+            self.current_channel = val.0;
+            *self.channels_hashed.get_mut(channel).unwrap() = val.1;
+        }
+    }
+
+    fn data_len(&self) -> usize {
+        self.channels.len()
+    }
+}
+
+fn extract_text_and_text_attributes_from_dom(
+    node: NodeRef,
+    builder: &mut RichTextBuilder,
+    current_pos: &mut usize,
+) {
+    match node.data() {
+        NodeData::Text(t) => {
+            let t = t.borrow();
+            builder.push(&*t);
+            *current_pos += t.len();
+        }
+
+        NodeData::Element(e) if e.name.local.as_ref() == "mx-reply" => (),
+
+        NodeData::Element(e) => {
+            let start = *current_pos;
+            for child in node.children() {
+                extract_text_and_text_attributes_from_dom(child, builder, current_pos);
+            }
+            let end = *current_pos;
+            if e.name.local.as_ref().starts_with('h') || e.name.local.as_ref() == "br" {
+                builder.push("\n");
+                *current_pos += 1;
+            }
+
+            let text_size = 16.0;
+            match e.name.local.as_ref() {
+                "em" => {
+                    builder.add_attributes_for_range(start..end)
+                        .add_attr(Attribute::Style(FontStyle::Italic));
+                }
+
+                "strong" => {
+                    builder.add_attributes_for_range(start..end)
+                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+                }
 
                 "u" => {
                     builder.add_attributes_for_range(start..end)
-                        .add_attr(Attribute::Underline(true));
+                        .add_attr(Attribute::Underline(true));
+                }
+
+                "code" => {
+                    builder.add_attributes_for_range(start..end)
+                        .add_attr(Attribute::FontFamily(FontFamily::MONOSPACE))
+                        .add_attr(Attribute::text_color(Color::grey8(200)));
+                }
+
+                "h1" => {
+                    builder.add_attributes_for_range(start..end)
+                        .add_attr(Attribute::size(text_size * 2.0))
+                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+                }
+
+                "h2" => {
+                    builder.add_attributes_for_range(start..end)
+                        .add_attr(Attribute::size(text_size * 1.5))
+                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+                }
+
+                "h3" => {
+                    builder.add_attributes_for_range(start..end)
+                        .add_attr(Attribute::size(text_size * 1.17))
+                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+                }
+
+                "h4" => {
+                    builder.add_attributes_for_range(start..end)
+                        .add_attr(Attribute::size(text_size))
+                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+                }
+
+                "h5" => {
+                    builder.add_attributes_for_range(start..end)
+                        .add_attr(Attribute::size(text_size * 0.83))
+                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+                }
+
+                "h6" => {
+                    builder.add_attributes_for_range(start..end)
+                        .add_attr(Attribute::size(text_size * 0.67))
+                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+                }
+
+                "span" if e.attributes.borrow().contains("data-mx-spoiler") => {
+                    // TODO
+                }
+
+                "a" => {
+                    let attrs = e.attributes.borrow();
+                    let mut href = attrs.get("href").unwrap_or("");
+                    let mut buffer = String::new();
+                    if !href.is_empty() && !href.contains("://") {
+                        buffer.push_str("https://");
+                        buffer.push_str(href);
+                        href = buffer.as_str();
+                    }
+
+                    builder.add_attributes_for_range(start..end)
+                        .add_attr(Attribute::text_color(Color::BLUE))
+                        .add_attr(Attribute::Underline(true))
+                        .link(LINK.with(Arc::from(href)));
+                }
+
+                _ => (),
+            }
+        }
+
+        NodeData::Comment(_) => (),
+        NodeData::ProcessingInstruction(_) => (),
+        NodeData::Doctype(_) => (),
+
+        NodeData::Document(_) => {
+            for child in node.children() {
+                extract_text_and_text_attributes_from_dom(child, builder, current_pos);
+            }
+        }
+
+        NodeData::DocumentFragment => (),
+    }
+}
+
+fn make_rich_text(
+    formatted: Option<&Value>,
+    default: Option<&Value>,
+    mark_edited: bool,
+) -> RichText {
+    let edited_message = "    (edited)";
+
+    match formatted {
+        Some(v) => {
+            let root = kuchiki::parse_html().one(v.as_string().unwrap().as_str());
+            let mut builder = RichTextBuilder::new();
+            let mut current_pos = 0;
+            extract_text_and_text_attributes_from_dom(root, &mut builder, &mut current_pos);
+            if mark_edited {
+                builder.push(edited_message);
+                builder.add_attributes_for_range(current_pos..)
+                    .add_attr(Attribute::text_color(Color::GRAY))
+                    .add_attr(Attribute::size(10.0));
+            }
+            builder.build()
+        }
+
+        None => {
+            let mut builder = RichTextBuilder::new();
+            let default = default.and_then(|v| v.as_string()).map(IString::as_str).unwrap_or("");
+            builder.push(default);
+            if mark_edited {
+                builder.push(edited_message);
+                builder.add_attributes_for_range(default.len()..)
+                    .add_attr(Attribute::text_color(Color::GRAY))
+                    .add_attr(Attribute::size(10.0));
+            }
+            builder.build()
+        }
+    }
+}
+
+fn make_message(
+    channel: Arc<String>,
+    txs: Senders,
+) -> impl Fn(&RoomEvent) -> Message {
+    move |event: &RoomEvent| {
+        let reply_to = event
+            .content
+            .get("m.relates_to")
+            .and_then(|v| v.get("m.in_reply_to"))
+            .and_then(|v| v.get("event_id"))
+            .and_then(Value::as_string)
+            .map(|v| Arc::new(String::from(v.as_str())));
+
+        let contents = match event.content.get("body").and_then(Value::as_string) {
+            Some(v) if reply_to.is_some() => Arc::new(String::from(chat::strip_reply_fallback(v.as_str()))),
+            Some(v) => Arc::new(String::from(v.as_str())),
+            None => Arc::new(String::new()),
+        };
+
+        let html = event
+            .content
+            .get("formatted_body")
+            .and_then(Value::as_string)
+            .map(|v| Arc::new(String::from(chat::strip_reply_fallback_html(v.as_str()))));
+
+        let formatted = make_rich_text(
+            html.as_deref().map(|v| Value::from(v.as_str())).as_ref(),
+            Some(&Value::from(contents.as_str())),
+            false,
+        );
+        let image = match event.content.get("msgtype") {
+            Some(v) if matches!(v.as_string(), Some(v) if v.as_str() == "m.image") => {
+                let url = event.content.get("url").unwrap().as_string().unwrap().as_str();
+                let info = event.content.get("info").unwrap();
+                let width = info.get("w").and_then(Value::to_u64).unwrap_or(0);
+                let height = info.get("h").and_then(Value::to_u64).unwrap_or(0);
+                ThumbnailState::Url(Arc::new(String::from(url)), width, height)
+            }
+
+            _ => ThumbnailState::None,
+        };
+
+        let edit = match event
+            .content
+            .get("m.relates_to")
+            .and_then(|v| v.get("rel_type"))
+            .and_then(Value::as_string)
+            .map(IString::as_str)
+        {
+            Some(v) if v == "m.replace" => {
+                if let Some(new) = event.content.get("m.new_content") {
+                    let contents =
+                        Arc::new(String::from(new.get("body").unwrap().as_string().unwrap().as_str()));
+                    let formatted =
+                        make_rich_text(new.get("formatted_body"), new.get("body"), true);
+                    Some(Edit {
+                        associated_event_id: Arc::new(String::from(
+                            event
+                                .content
+                                .get("m.relates_to")
+                                .unwrap()
+                                .get("event_id")
+                                .unwrap()
+                                .as_string()
+                                .unwrap()
+                                .as_str(),
+                        )),
+                        contents,
+                        formatted,
+                    })
+                } else {
+                    None
+                }
+            }
+
+            _ => None,
+        };
+
+        let redacts = if event.type_.as_str() == "m.room.redaction" {
+            event.redacts.clone().or_else(|| {
+                event
+                    .content
+                    .get("redacts")
+                    .and_then(Value::as_string)
+                    .map(|v| Arc::new(String::from(v.as_str())))
+            })
+        } else {
+            None
+        };
+
+        let annotation = match event.content.get("m.relates_to") {
+            Some(relates_to) if event.type_.as_str() == "m.reaction" => {
+                let rel_type = relates_to.get("rel_type").and_then(Value::as_string).map(IString::as_str);
+                let associated = relates_to.get("event_id").and_then(Value::as_string);
+                let key = relates_to.get("key").and_then(Value::as_string);
+                match (rel_type, associated, key) {
+                    (Some("m.annotation"), Some(associated), Some(key)) => Some(Annotation {
+                        associated_event_id: Arc::new(String::from(associated.as_str())),
+                        event_id: event.event_id.clone(),
+                        sender: event.sender.clone(),
+                        key: Arc::new(String::from(key.as_str())),
+                    }),
+
+                    _ => None,
+                }
+            }
+
+            _ => None,
+        };
+
+        let mut message = Message {
+            event_type: event.type_.clone(),
+            edit,
+            redacts,
+            redacted: false,
+            annotation,
+            reactions: Vector::new(),
+            picking_reaction: false,
+            sender: event.sender.clone(),
+            avatar: AvatarState::Name(event.sender.clone()),
+            event_id: event.event_id.clone(),
+            transaction_id: event.unsigned.transaction_id.clone(),
+            send_state: SendState::Sent,
+            contents: contents.clone(),
+            html,
+            formatted,
+            reply_to,
+            reply_preview: None,
+            fetching_reply: false,
+            media_url: match &image {
+                ThumbnailState::Url(url, _, _) => Some(url.clone()),
+                _ => None,
+            },
+            image,
+            read_by: Vector::new(),
+            timestamp: event.origin_server_ts,
+            editing_message: contents,
+            editing: false,
+            channel: channel.clone(),
+            reply: None,
+            attachment: None,
+            txs: txs.clone(),
+        };
+
+        if event.unsigned.redacted_because.is_some() {
+            message.redact();
+        }
+        message
+    }
+}
+
+struct MessageScrollController;
+
+impl<W> widget::Controller<Chat, widget::Scroll<Chat, W>> for MessageScrollController
+    where W: Widget<Chat>
+{
+    fn event(&mut self, child: &mut widget::Scroll<Chat, W>, ctx: &mut EventCtx, event: &Event, data: &mut Chat, env: &Env) {
+        match event {
+            Event::Wheel(wheel) => {
+                if let Some(channel) = data.channels_hashed.get_mut(&data.current_channel) {
+                    if channel.bottom && wheel.wheel_delta.y < 0.0 {
+                        channel.bottom = false;
+                    }
+
+                    if channel.fetch_failed && wheel.wheel_delta.y < 0.0 {
+                        channel.fetch_failed = false;
+                        channel.fetching_old = false;
+                    }
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(SCROLLED) && data.scroll.is_some() => {
+                data.scroll = None;
+                if let Some(channel) = data.channels_hashed.get_mut(&data.current_channel) {
+                    channel.fetching_old = false;
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(FETCH_FROM_ROOM_FAIL) => {
+                let (channel, error) = cmd.get_unchecked(FETCH_FROM_ROOM_FAIL);
+                if let Some(channel) = data.channels_hashed.get_mut(channel) {
+                    match error.errcode() {
+                        // we aren't allowed to see any further back, so stop asking
+                        Some(ErrorCode::Forbidden) | Some(ErrorCode::NotFound) => channel.top = true,
+
+                        // wait for the user to scroll up again before retrying
+                        _ => {
+                            eprintln!("error fetching messages for {}: {}", channel.id, error);
+                            channel.fetch_failed = true;
+                        }
+                    }
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(FETCH_FROM_ROOM) => {
+                let (channel, state) = cmd.get_unchecked(FETCH_FROM_ROOM);
+                if let Some(channel) = data.channels_hashed.get_mut(channel) {
+                    channel.prev_batch = state.end.clone();
+                    if channel.first_batch.is_empty() {
+                        channel.first_batch = channel.prev_batch.clone();
+                    }
+                    channel.top = state.chunk.is_empty();
+                    data.scroll = Some(child.child_size().height);
+
+                    let messages = state
+                        .chunk
+                        .iter()
+                        .map(make_message(channel.id.clone(), data.txs.clone()))
+                        .collect();
+                    channel.add_timeline(messages, true, &data.user_id);
+                }
+            }
+
+            _ => (),
+        }
+
+        child.event(ctx, event, data, env);
+
+        if let Some(channel) = data.channels_hashed.get_mut(&data.current_channel) {
+            if !channel.bottom && child.viewport_rect().contains(Point {
+                x: 0.0,
+                y: child.child_size().height - 0.01,
+            }) {
+                channel.bottom = true;
+            }
+
+            // syncs keep landing while the user is away, and those shouldn't count as read
+            let present = matches!(data.last_input, Some(v) if v.elapsed() < AWAY_AFTER);
+            if channel.bottom && present {
+                let last = channel.messages.iter().rev().find(|v| !v.event_id.is_empty()).map(|v| v.event_id.clone());
+                if let Some(last) = last {
+                    if last != channel.read_up_to {
+                        match data.txs.action_tx.try_send(UserAction::MarkRead(channel.id.clone(), last.clone())) {
+                            Ok(_) => {
+                                channel.read_up_to = last;
+                                channel.highlight_count = 0;
+                                channel.notification_count = 0;
+                            }
+
+                            // nothing's changed as far as the server knows, so try again on the next event
+                            Err(TrySendError::Full(_)) => (),
+                            Err(TrySendError::Closed(_)) => panic!("oh no"),
+                        }
+                    }
+                }
+            }
+
+            if !channel.fetching_old && !channel.top && (child.viewport_rect().contains(Point {
+                x: 0.0,
+                y: 0.0,
+            }) || child.child_size().height == 0.0) {
+                let filter = chat::RoomEventFilter::new().limit(50).types(&MESSAGE_TYPES);
+                match data.txs.sync_tx.try_send(Syncing::FetchFromRoom(channel.id.clone(), channel.prev_batch.clone(), filter)) {
+                    Ok(_) => (),
+                    Err(TrySendError::Full(_)) => panic!("oh no"),
+                    Err(TrySendError::Closed(_)) => panic!("aaaaa"),
+                }
+
+                channel.fetching_old = true;
+            }
+        }
+    }
+
+    fn lifecycle(
+        &mut self,
+        child: &mut widget::Scroll<Chat, W>,
+        ctx: &mut druid::LifeCycleCtx,
+        event: &druid::LifeCycle,
+        data: &Chat,
+        env: &Env,
+    ) {
+        child.lifecycle(ctx, event, data, env);
+        if let Some(channel) = data.channels_hashed.get(&data.current_channel) {
+            if channel.bottom {
+                child.scroll_to_on_axis(Axis::Vertical, f64::INFINITY);
+            } else if let Some(scroll) = data.scroll {
+                if (scroll - child.child_size().height).abs() > 0.001 {
+                    child.scroll_to_on_axis(Axis::Vertical, child.child_size().height - scroll);
+                    ctx.submit_command(SCROLLED);
+                }
+            }
+        }
+    }
+
+    fn update(&mut self, child: &mut widget::Scroll<Chat, W>, ctx: &mut druid::UpdateCtx, old_data: &Chat, data: &Chat, env: &Env) {
+        child.update(ctx, old_data, data, env);
+    }
+}
+
+struct ChatController;
+
+impl<W> widget::Controller<Chat, W> for ChatController
+where
+    W: widget::Widget<Chat>,
+{
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut Chat,
+        env: &Env,
+    ) {
+        if matches!(event, Event::MouseDown(_) | Event::MouseMove(_) | Event::Wheel(_) | Event::KeyDown(_)) {
+            data.last_input = Some(Instant::now());
+        }
+
+        match event {
+            Event::WindowConnected => {
+                if data.logged_in {
+                    data.sync();
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(LOGGED_IN) => {
+                data.user_id = cmd.get_unchecked(LOGGED_IN).clone();
+                data.logged_in = true;
+                data.login.logging_in = false;
+                data.login.password = Arc::new(String::new());
+                data.sync();
+            }
+
+            Event::Command(cmd) if cmd.is(LOGIN_FAIL) => {
+                let error = cmd.get_unchecked(LOGIN_FAIL);
+                data.login.logging_in = false;
+                data.login.error = Arc::new(match error {
+                    Error::Transport(e) => format!("could not reach the homeserver: {}", e),
+                    Error::Status { errcode: ErrorCode::Forbidden, .. } => String::from("incorrect username or password"),
+                    Error::Status { errcode: ErrorCode::LimitExceeded, .. } => String::from("too many login attempts, try again later"),
+                    Error::Status { error, .. } if !error.is_empty() => format!("could not log in: {}", error),
+                    _ => format!("could not log in: {}", error),
+                });
+            }
+
+            Event::Command(cmd) if cmd.is(SYNC_FAIL) && (!data.logged_in || cmd.get_unchecked(SYNC_FAIL).0 != data.session) => (),
+
+            Event::Command(cmd) if cmd.is(SYNC) && (!data.logged_in || cmd.get_unchecked(SYNC).0 != data.session) => (),
+
+            Event::Command(cmd) if cmd.is(SYNC_FAIL) && matches!(cmd.get_unchecked(SYNC_FAIL).1.errcode(), Some(ErrorCode::UnknownToken) | Some(ErrorCode::MissingToken)) => {
+                data.log_out();
+                data.login.error = Arc::new(String::from("your session has expired, please log in again"));
+            }
+
+            Event::Command(cmd) if cmd.is(SYNC_FAIL) => {
+                let (_, error) = cmd.get_unchecked(SYNC_FAIL);
+                eprintln!("error syncing: {}", error);
+
+                let mut delay = sync_backoff(data.sync_failures);
+                if let Error::Status { retry_after_ms: Some(ms), .. } = error {
+                    delay = delay.max(Duration::from_millis(*ms));
+                }
+
+                data.sync_failures += 1;
+                data.connection = match error {
+                    Error::Transport(e) if e.is_connect() || e.is_timeout() => Connection::Offline,
+                    _ => Connection::Reconnecting,
+                };
+                data.retry_timer = ctx.request_timer(delay);
+            }
+
+            Event::Timer(token) if *token == data.retry_timer && data.logged_in => {
+                data.retry_timer = TimerToken::INVALID;
+                data.sync();
+            }
+
+            Event::Command(cmd) if cmd.is(RECONNECT) => {
+                // only when a retry is pending, otherwise there's already a sync in flight
+                if data.retry_timer != TimerToken::INVALID {
+                    data.retry_timer = TimerToken::INVALID;
+                    data.sync();
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(SYNC) => {
+                let (_, sync) = cmd.get_unchecked(SYNC);
+                let mut changed_spaces = vec![];
+                if let Some(rooms) = &sync.rooms {
+                    if let Some(join) = &rooms.join {
+                        for (id, joined) in join.iter() {
+                            data.invites.retain(|v| &v.room_id != id);
+                            let messages = joined
+                                .timeline
+                                .events
+                                .iter()
+                                .filter(|v| v.state_key.is_none())
+                                .map(make_message(id.clone(), data.txs.clone()))
+                                .collect();
+
+                            let is_new = !data.channels_hashed.contains_key(id);
+                            if is_new {
+                                // backfill from right before whatever the timeline already gave us
+                                let mut channel = Channel::new(id.clone(), id.clone());
+                                channel.prev_batch = Arc::new(joined.timeline.prev_batch.clone());
+                                data.channels_hashed.insert(id.clone(), channel);
+                                data.channels.push_back(id.clone());
+                            }
+
+                            if let Some(channel) = data.channels_hashed.get_mut(id) {
+                                let mut hierarchy_changed = false;
+                                let mut name_changed = channel.naming.apply_summary(&joined.summary) || is_new;
+
+                                // later changes arrive in the timeline instead of the state block
+                                let state = joined
+                                    .state
+                                    .events
+                                    .iter()
+                                    .map(|v| (&v.type_, &v.state_key, &v.content))
+                                    .chain(joined.timeline.events.iter().filter_map(|v| Some((&v.type_, v.state_key.as_ref()?, &v.content))));
+                                for (event_type, state_key, content) in state {
+                                    name_changed |= channel.naming.apply_state(event_type, state_key, content);
+                                    if event_type.as_str() == "m.room.create" {
+                                        let room_type = content.get("type").and_then(Value::as_string);
+                                        if matches!(room_type, Some(v) if v.as_str() == "m.space") && !channel.is_space {
+                                            channel.is_space = true;
+                                            hierarchy_changed = true;
+                                        }
+                                    } else if event_type.as_str() == "m.space.child" {
+                                        hierarchy_changed |= channel.is_space;
+                                    } else if event_type.as_str() == "m.room.power_levels" {
+                                        channel.power_levels = Arc::new(ijson::from_value(content).unwrap_or_default());
+                                    } else if event_type.as_str() == "m.room.canonical_alias" {
+                                        let alias = content.get("alias").and_then(Value::as_string);
+                                        let alt_aliases = content.get("alt_aliases").and_then(Value::as_array);
+                                        channel.aliases = alias
+                                            .into_iter()
+                                            .chain(alt_aliases.into_iter().flat_map(|v| v.iter().filter_map(Value::as_string)))
+                                            .map(|v| Arc::new(String::from(v.as_str())))
+                                            .collect();
+                                    }
+                                }
+
+                                if name_changed {
+                                    channel.name = Arc::new(channel.naming.display_name(&data.user_id));
+                                }
+
+                                if hierarchy_changed {
+                                    changed_spaces.push(id.clone());
+                                }
+
+                                for event in joined.account_data.events.iter() {
+                                    if let AccountDataEvent::Tag { content } = event {
+                                        channel.tags = content.tags.iter().map(|(k, v)| (k.clone(), v.order)).collect();
+                                    }
+                                }
+
+                                channel.add_timeline(messages, false, &data.user_id);
+
+                                for event in joined.ephemeral.events.iter() {
+                                    match event {
+                                        EphemeralEvent::Typing { content } => {
+                                            channel.typing = content
+                                                .user_ids
+                                                .iter()
+                                                .filter(|v| **v != data.user_id)
+                                                .cloned()
+                                                .collect();
+                                        }
+
+                                        EphemeralEvent::Receipt { content } => {
+                                            for (event_id, receipts) in content.iter() {
+                                                let users = receipts
+                                                    .iter()
+                                                    .filter(|(k, _)| k.as_str() == "m.read" || k.as_str() == "m.read.private")
+                                                    .flat_map(|(_, v)| v.keys());
+                                                for user in users {
+                                                    if *user != data.user_id {
+                                                        channel.set_receipt(user.clone(), event_id.clone());
+                                                    } else if *event_id == channel.read_up_to {
+                                                        channel.read_echoed = event_id.clone();
+                                                    }
+                                                }
+                                            }
+                                            channel.update_read_by();
+                                        }
+
+                                        EphemeralEvent::Other => (),
+                                    }
+                                }
+
+                                if channel.read_echoed == channel.read_up_to {
+                                    channel.highlight_count = joined.unread_notifications.highlight_count;
+                                    channel.notification_count = joined.unread_notifications.notification_count;
+                                }
+                            }
+                        }
+                    }
+                }
+
+                if let Some(rooms) = &sync.rooms {
+                    if let Some(invite) = &rooms.invite {
+                        for (id, room) in invite.iter() {
+                            let invite = Invite::new(id.clone(), room, &data.user_id);
+                            match data.invites.iter().position(|v| v.room_id == invite.room_id) {
+                                Some(i) => data.invites.set(i, invite),
+                                None => data.invites.push_back(invite),
+                            };
+                        }
+                    }
+
+                    if let Some(leave) = &rooms.leave {
+                        for id in leave.keys() {
+                            data.remove_channel(id);
+                        }
+                    }
+                }
+
+                if let Some(account_data) = &sync.account_data {
+                    for event in account_data.events.iter() {
+                        if let AccountDataEvent::Direct { content } = event {
+                            data.direct = content
+                                .iter()
+                                .flat_map(|(user, rooms)| rooms.iter().map(move |room| (room.clone(), user.clone())))
+                                .collect();
+                        }
+                    }
+                }
+                data.apply_direct();
+                if !changed_spaces.is_empty() {
+                    data.fetch_hierarchy(changed_spaces);
+                }
+
+                data.next_batch = sync.next_batch.clone();
+                data.sync_failures = 0;
+                data.connection = Connection::Connected;
+                data.sync();
+            }
+
+            Event::Command(cmd) if cmd.is(MESSAGE_SENT) => {
+                let (room_id, txn_id, event_id) = cmd.get_unchecked(MESSAGE_SENT);
+                if let Some(channel) = data.channels_hashed.get_mut(room_id) {
+                    for message in channel.messages.iter_mut() {
+                        if message.transaction_id.as_ref() == Some(txn_id) {
+                            if message.event_id.is_empty() {
+                                message.event_id = event_id.clone();
+                            }
+                            message.send_state = SendState::Sent;
+                        }
+                    }
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(MESSAGE_SEND_FAIL) => {
+                let (room_id, txn_id, error) = cmd.get_unchecked(MESSAGE_SEND_FAIL);
+                eprintln!("error sending {} to {}: {}", txn_id, room_id, error);
+                if let Some(channel) = data.channels_hashed.get_mut(room_id) {
+                    for message in channel.messages.iter_mut() {
+                        if message.transaction_id.as_ref() == Some(txn_id) && message.send_state == SendState::Sending {
+                            message.send_state = SendState::Failed;
+                        }
+                    }
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(commands::OPEN_FILE) => {
+                let path = cmd.get_unchecked(commands::OPEN_FILE).path().to_path_buf();
+                if let Some(channel) = data.channels_hashed.get_mut(&data.current_channel) {
+                    let message = Message::local_file_echo(
+                        data.current_channel.clone(),
+                        data.user_id.clone(),
+                        path,
+                        data.txs.clone(),
+                    );
+                    message.send();
+                    channel.bottom = true;
+                    channel.push_message(message);
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(OPEN_IMAGE) => {
+                let (url, filename) = cmd.get_unchecked(OPEN_IMAGE);
+                let failed = matches!(data.viewers.get(url), Some(v) if v.error.is_some());
+                if failed || !data.viewers.contains_key(url) {
+                    data.viewers.insert(url.clone(), Viewer::new(url.clone(), filename.clone()));
+                    match data.txs.media_tx.try_send(MediaFetch::Download(url.clone())) {
+                        Ok(_) => (),
+                        Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+                        Err(TrySendError::Closed(_)) => panic!("oh no"),
+                    }
+                }
+                if let Some(viewer) = data.viewers.get_mut(url) {
+                    viewer.windows += 1;
+                }
+
+                ctx.new_window(
+                    WindowDesc::new(build_image_viewer(url.clone()))
+                        .title(filename.as_str())
+                        .window_size((800., 600.)),
+                );
+            }
+
+            Event::Command(cmd) if cmd.is(DOWNLOAD_PROGRESS) => {
+                let (url, progress) = cmd.get_unchecked(DOWNLOAD_PROGRESS);
+                if let Some(viewer) = data.viewers.get_mut(url) {
+                    viewer.progress = *progress;
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(DOWNLOAD_DONE) => {
+                let (url, image, path) = cmd.get_unchecked(DOWNLOAD_DONE);
+                if let Some(viewer) = data.viewers.get_mut(url) {
+                    viewer.progress = 1.0;
+                    viewer.image = Some(Arc::new(image.clone()));
+                    viewer.path = Some(path.clone());
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(DOWNLOAD_FAIL) => {
+                let (url, error) = cmd.get_unchecked(DOWNLOAD_FAIL);
+                if let Some(viewer) = data.viewers.get_mut(url) {
+                    viewer.error = Some(error.clone());
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(SELECT_SPACE) => {
+                data.selected_space = cmd.get_unchecked(SELECT_SPACE).clone();
+                if let Some(space) = &data.selected_space {
+                    data.fetch_hierarchy(vec![space.clone()]);
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(SPACE_HIERARCHY) => {
+                let (space, rooms) = cmd.get_unchecked(SPACE_HIERARCHY);
+                let children = rooms
+                    .iter()
+                    .filter(|v| v.room_id != *space)
+                    .map(|v| v.room_id.clone())
+                    .collect();
+                data.space_children.insert(space.clone(), children);
+            }
+
+            Event::Command(cmd) if cmd.is(ACCEPT_INVITE) => {
+                let room = cmd.get_unchecked(ACCEPT_INVITE);
+                let via = data.invites.iter().find(|v| &v.room_id == room).and_then(|v| v.via.clone());
+                data.join(room.clone(), via.into_iter().collect());
+            }
+
+            Event::Command(cmd) if cmd.is(DECLINE_INVITE) => {
+                let room = cmd.get_unchecked(DECLINE_INVITE);
+                match data.txs.action_tx.try_send(UserAction::RejectInvite(room.clone())) {
+                    Ok(_) => (),
+                    Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+                    Err(TrySendError::Closed(_)) => panic!("oh no"),
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(ROOM_LEFT) => {
+                data.remove_channel(cmd.get_unchecked(ROOM_LEFT));
+            }
+
+            Event::Command(cmd) if cmd.is(MEMBERSHIP_FAIL) => {
+                let (room, action, error) = cmd.get_unchecked(MEMBERSHIP_FAIL);
+                let name = match data.invites.iter().position(|v| &v.room_id == room) {
+                    Some(i) => {
+                        let mut invite = data.invites[i].clone();
+                        invite.pending = false;
+                        let name = invite.name.clone();
+                        data.invites.set(i, invite);
+                        name
+                    }
+
+                    None => data.channels_hashed.get(room).map(|v| v.display_name()).unwrap_or_else(|| room.clone()),
+                };
+                data.notice = Some(Arc::new(format!("couldn't {} {}: {}", action, name, error)));
+            }
+
+            Event::Command(cmd) if cmd.is(DISPLAY_NAME) => {
+                let (user, name) = cmd.get_unchecked(DISPLAY_NAME);
+                for channel in data.channels_hashed.values_mut() {
+                    if let Some(dm) = &mut channel.dm {
+                        if &dm.user_id == user {
+                            dm.display_name = Some(name.clone());
+                        }
+                    }
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(DIRECT_CREATED) => {
+                let (room, user) = cmd.get_unchecked(DIRECT_CREATED);
+                data.direct.insert(room.clone(), user.clone());
+                if !data.channels_hashed.contains_key(room) {
+                    data.channels_hashed.insert(room.clone(), Channel::new(room.clone(), user.clone()));
+                    data.channels.push_back(room.clone());
+                }
+                data.apply_direct();
+                data.select_channel(room.clone());
+            }
+
+            Event::Command(cmd) if cmd.is(CREATE_ROOM) => {
+                if let Some(new_room) = &mut data.new_room {
+                    new_room.creating = true;
+                    new_room.error = None;
+                    match data.txs.action_tx.try_send(UserAction::CreateRoom(new_room.options())) {
+                        Ok(_) => (),
+                        Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+                        Err(TrySendError::Closed(_)) => panic!("oh no"),
+                    }
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(CANCEL_NEW_ROOM) => {
+                data.new_room = None;
+            }
+
+            Event::Command(cmd) if cmd.is(ROOM_CREATED) => {
+                let (room, name, is_space) = cmd.get_unchecked(ROOM_CREATED);
+                if !data.channels_hashed.contains_key(room) {
+                    let mut channel = Channel::new(room.clone(), name.clone());
+                    channel.is_space = *is_space;
+                    data.channels_hashed.insert(room.clone(), channel);
+                    data.channels.push_back(room.clone());
+                }
+
+                data.new_room = None;
+                if *is_space {
+                    data.selected_space = Some(room.clone());
+                    data.fetch_hierarchy(vec![room.clone()]);
+                } else {
+                    data.selected_space = None;
+                    data.select_channel(room.clone());
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(ROOM_CREATE_FAIL) => {
+                if let Some(new_room) = &mut data.new_room {
+                    new_room.creating = false;
+                    new_room.error = Some(cmd.get_unchecked(ROOM_CREATE_FAIL).clone());
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(ROOM_STATE) => {
+                let (room, state) = cmd.get_unchecked(ROOM_STATE);
+                if let Some(settings) = data.settings.as_mut().filter(|v| &v.room == room) {
+                    settings.load(state);
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(ROOM_STATE_FAIL) => {
+                let (room, error) = cmd.get_unchecked(ROOM_STATE_FAIL);
+                if let Some(settings) = data.settings.as_mut().filter(|v| &v.room == room) {
+                    settings.error = Some(error.clone());
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(SAVE_SETTINGS) => {
+                if let Some(settings) = &mut data.settings {
+                    match settings.changes() {
+                        Ok(changes) => {
+                            for (event_type, content) in changes {
+                                match data.txs.action_tx.try_send(UserAction::SetState(settings.room.clone(), event_type, content)) {
+                                    Ok(_) => (),
+                                    Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+                                    Err(TrySendError::Closed(_)) => panic!("oh no"),
+                                }
+                            }
+                            data.settings = None;
+                        }
+
+                        Err(e) => settings.error = Some(Arc::new(e)),
+                    }
                 }
+            }
+
+            Event::Command(cmd) if cmd.is(CLOSE_SETTINGS) => {
+                data.settings = None;
+            }
+
+            Event::Command(cmd) if cmd.is(STATE_SEND_FAIL) => {
+                let (room, event_type, error) = cmd.get_unchecked(STATE_SEND_FAIL);
+                let name = data.channels_hashed.get(room).map(|v| v.display_name()).unwrap_or_else(|| room.clone());
+                data.notice = Some(Arc::new(format!("couldn't update {} in {}: {}", event_type, name, error)));
+            }
+
+            Event::Command(cmd) if cmd.is(MODERATE_FAIL) => {
+                let (room, action, user, error) = cmd.get_unchecked(MODERATE_FAIL);
+                let name = data.channels_hashed.get(room).map(|v| v.display_name()).unwrap_or_else(|| room.clone());
+                data.notice = Some(Arc::new(format!("couldn't {} {} in {}: {}", action.command(), user, name, error)));
+            }
+
+            Event::Command(cmd) if cmd.is(REPLY) => {
+                data.replying_to = Some(cmd.get_unchecked(REPLY).clone());
+            }
 
-                "code" => {
-                    builder.add_attributes_for_range(start..end)
-                        .add_attr(Attribute::FontFamily(FontFamily::MONOSPACE))
-                        .add_attr(Attribute::text_color(Color::grey8(200)));
+            Event::Command(cmd) if cmd.is(FETCH_EVENT) => {
+                let (room_id, event) = cmd.get_unchecked(FETCH_EVENT);
+                if let Some(channel) = data.channels_hashed.get_mut(room_id) {
+                    let parent = make_message(room_id.clone(), data.txs.clone())(event);
+                    let preview = ReplyPreview::of(&parent);
+                    for msg in channel.messages.iter_mut() {
+                        if msg.reply_to.as_ref() == Some(&event.event_id) {
+                            msg.reply_preview = Some(preview.clone());
+                            msg.fetching_reply = false;
+                        }
+                    }
+                    channel.resolve_replies();
                 }
+            }
 
-                "h1" => {
-                    builder.add_attributes_for_range(start..end)
-                        .add_attr(Attribute::size(text_size * 2.0))
-                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+            Event::Command(cmd) if cmd.is(FETCH_EVENT_FAIL) => {
+                let (room_id, event_id, error) = cmd.get_unchecked(FETCH_EVENT_FAIL);
+                eprintln!("error fetching {} in {}: {}", event_id, room_id, error);
+                if let Some(channel) = data.channels_hashed.get_mut(room_id) {
+                    let preview = ReplyPreview {
+                        sender: Arc::new(String::new()),
+                        text: Arc::new(String::from("original message unavailable")),
+                    };
+                    for msg in channel.messages.iter_mut() {
+                        if msg.reply_to.as_ref() == Some(event_id) {
+                            msg.reply_preview = Some(preview.clone());
+                            msg.fetching_reply = false;
+                        }
+                    }
+                    channel.resolve_replies();
                 }
+            }
 
-                "h2" => {
-                    builder.add_attributes_for_range(start..end)
-                        .add_attr(Attribute::size(text_size * 1.5))
-                        .add_attr(Attribute::Weight(FontWeight::new(700)));
-                }
+            Event::Command(cmd) if cmd.is(TOGGLE_REACTION) => {
+                let (room_id, target, key) = cmd.get_unchecked(TOGGLE_REACTION);
+                let user_id = data.user_id.clone();
+                if let Some(channel) = data.channels_hashed.get_mut(room_id) {
+                    // the last toggle hasn't come back yet, so another one would only send a duplicate
+                    if !channel.pending_reactions.iter().any(|(t, k, _)| t == target && k == key) {
+                        let own = channel
+                            .messages
+                            .iter()
+                            .find(|v| &v.event_id == target)
+                            .and_then(|v| v.reactions.iter().find(|v| &v.key == key))
+                            .and_then(|v| v.annotations.iter().find(|v| v.sender == user_id))
+                            .map(|v| v.event_id.clone());
+                        channel.pending_reactions.push_back((target.clone(), key.clone(), own.is_none()));
 
-                "h3" => {
-                    builder.add_attributes_for_range(start..end)
-                        .add_attr(Attribute::size(text_size * 1.17))
-                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+                        let action = match own {
+                            Some(event_id) => UserAction::RemoveReaction(room_id.clone(), target.clone(), key.clone(), event_id),
+                            None => UserAction::SendReaction(room_id.clone(), target.clone(), key.clone()),
+                        };
+                        match data.txs.action_tx.try_send(action) {
+                            Ok(_) => (),
+                            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+                            Err(TrySendError::Closed(_)) => panic!("oh no"),
+                        }
+                    }
                 }
+            }
 
-                "h4" => {
-                    builder.add_attributes_for_range(start..end)
-                        .add_attr(Attribute::size(text_size))
-                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+            // the server never heard about it, so whatever it says is as current as it gets
+            Event::Command(cmd) if cmd.is(MARK_READ_FAIL) => {
+                if let Some(channel) = data.channels_hashed.get_mut(cmd.get_unchecked(MARK_READ_FAIL)) {
+                    channel.read_echoed = channel.read_up_to.clone();
                 }
+            }
 
-                "h5" => {
-                    builder.add_attributes_for_range(start..end)
-                        .add_attr(Attribute::size(text_size * 0.83))
-                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+            Event::Command(cmd) if cmd.is(REACTION_FAIL) => {
+                let (room_id, target, key) = cmd.get_unchecked(REACTION_FAIL);
+                if let Some(channel) = data.channels_hashed.get_mut(room_id) {
+                    channel.pending_reactions.retain(|(t, k, _)| t != target || k != key);
                 }
+            }
 
-                "h6" => {
-                    builder.add_attributes_for_range(start..end)
-                        .add_attr(Attribute::size(text_size * 0.67))
-                        .add_attr(Attribute::Weight(FontWeight::new(700)));
+            Event::Command(cmd) if cmd.is(DISCARD_MESSAGE) => {
+                let (room_id, txn_id) = cmd.get_unchecked(DISCARD_MESSAGE);
+                if let Some(channel) = data.channels_hashed.get_mut(room_id) {
+                    channel.messages.retain(|v| v.transaction_id.as_ref() != Some(txn_id));
                 }
+            }
 
-                "span" if e.attributes.borrow().contains("data-mx-spoiler") => {
-                    // TODO
-                }
+            Event::KeyDown(key) if data.logged_in && (key.mods.ctrl() || key.mods.meta()) && matches!(&key.key, Key::Character(c) if c.eq_ignore_ascii_case("k")) => {
+                data.switcher = Switcher {
+                    open: true,
+                    query: Arc::new(String::new()),
+                    selected: 0,
+                };
+                ctx.submit_command(FOCUS_SWITCHER);
+                ctx.set_handled();
+            }
 
-                "a" => {
-                    let attrs = e.attributes.borrow();
-                    let mut href = attrs.get("href").unwrap_or("");
-                    let mut buffer = String::new();
-                    if !href.is_empty() && !href.contains("://") {
-                        buffer.push_str("https://");
-                        buffer.push_str(href);
-                        href = buffer.as_str();
-                    }
+            Event::Command(cmd) if cmd.is(SWITCH_TO) => {
+                data.switcher.open = false;
+                data.select_channel(cmd.get_unchecked(SWITCH_TO).clone());
+            }
 
-                    builder.add_attributes_for_range(start..end)
-                        .add_attr(Attribute::text_color(Color::BLUE))
-                        .add_attr(Attribute::Underline(true))
-                        .link(LINK.with(Arc::from(href)));
+            Event::Command(cmd) if cmd.is(LINK) => {
+                let link = cmd.get_unchecked(LINK);
+                if open::that(&**link).is_err() {
+                    eprintln!("error opening link {}", link);
                 }
-
-                _ => (),
             }
-        }
-
-        NodeData::Comment(_) => (),
-        NodeData::ProcessingInstruction(_) => (),
-        NodeData::Doctype(_) => (),
 
-        NodeData::Document(_) => {
-            for child in node.children() {
-                extract_text_and_text_attributes_from_dom(child, builder, current_pos);
+            Event::WindowDisconnected => {
+                while let Err(TrySendError::Full(_)) = data.txs.sync_tx.try_send(Syncing::Quit) {}
+                while let Err(TrySendError::Full(_)) = data.txs.action_tx.try_send(UserAction::Quit) {}
+                while let Err(TrySendError::Full(_)) = data.txs.media_tx.try_send(MediaFetch::Quit) {}
             }
+
+            _ => (),
         }
 
-        NodeData::DocumentFragment => (),
+        child.event(ctx, event, data, env)
     }
 }
 
-fn make_rich_text(
-    formatted: Option<&Value>,
-    default: Option<&Value>,
-    mark_edited: bool,
-) -> RichText {
-    let edited_message = "    (edited)";
+struct SwitcherController;
 
-    match formatted {
-        Some(v) => {
-            let root = kuchiki::parse_html().one(v.as_string().unwrap().as_str());
-            let mut builder = RichTextBuilder::new();
-            let mut current_pos = 0;
-            extract_text_and_text_attributes_from_dom(root, &mut builder, &mut current_pos);
-            if mark_edited {
-                builder.push(edited_message);
-                builder.add_attributes_for_range(current_pos..)
-                    .add_attr(Attribute::text_color(Color::GRAY))
-                    .add_attr(Attribute::size(10.0));
+impl<W> widget::Controller<Chat, W> for SwitcherController
+where
+    W: Widget<Chat>,
+{
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut Chat,
+        env: &Env,
+    ) {
+        match event {
+            Event::Command(cmd) if cmd.is(FOCUS_SWITCHER) => {
+                ctx.request_focus();
             }
-            builder.build()
-        }
 
-        None => {
-            let mut builder = RichTextBuilder::new();
-            let default = default.and_then(|v| v.as_string()).map(IString::as_str).unwrap_or("");
-            builder.push(default);
-            if mark_edited {
-                builder.push(edited_message);
-                builder.add_attributes_for_range(default.len()..)
-                    .add_attr(Attribute::text_color(Color::GRAY))
-                    .add_attr(Attribute::size(10.0));
+            Event::KeyDown(key) if key.key == Key::Escape => {
+                data.switcher.open = false;
+                ctx.set_handled();
             }
-            builder.build()
+
+            Event::KeyDown(key) if key.key == Key::ArrowDown => {
+                let count = data.switcher_results().len();
+                if data.switcher.selected + 1 < count {
+                    data.switcher.selected += 1;
+                }
+                ctx.set_handled();
+            }
+
+            Event::KeyDown(key) if key.key == Key::ArrowUp => {
+                data.switcher.selected = data.switcher.selected.saturating_sub(1);
+                ctx.set_handled();
+            }
+
+            Event::KeyDown(key) if key.key == Key::Enter => {
+                if let Some(item) = data.switcher_results().get(data.switcher.selected) {
+                    ctx.submit_command(SWITCH_TO.with(item.id.clone()));
+                }
+                ctx.set_handled();
+            }
+
+            _ => (),
+        }
+
+        let query = data.switcher.query.clone();
+        child.event(ctx, event, data, env);
+        if query != data.switcher.query {
+            data.switcher.selected = 0;
         }
     }
 }
 
-fn make_message(
-    channel: Arc<String>,
-    txs: Senders,
-) -> impl Fn(&RoomEvent) -> Message {
-    move |event: &RoomEvent| {
-        let formatted = make_rich_text(
-            event.content.get("formatted_body"),
-            event.content.get("body"),
-            false,
-        );
-        let image = match event.content.get("msgtype") {
-            Some(v) if matches!(v.as_string(), Some(v) if v.as_str() == "m.image") => {
-                let url = event.content.get("url").unwrap().as_string().unwrap().as_str();
-                let info = event.content.get("info").unwrap();
-                let width = info.get("w").and_then(Value::to_u64).unwrap_or(0);
-                let height = info.get("h").and_then(Value::to_u64).unwrap_or(0);
-                ThumbnailState::Url(Arc::new(String::from(url)), width, height)
+fn create_switcher_item() -> impl Widget<SwitcherItem> {
+    widget::Flex::row()
+        .with_child(widget::Label::dynamic(|data: &SwitcherItem, _| (*data.name).clone()))
+        .with_spacer(8.0)
+        .with_child(
+            widget::Label::dynamic(|data: &SwitcherItem, _| match &data.alias {
+                Some(v) => (**v).clone(),
+                None => String::new(),
+            })
+            .with_text_color(Color::GRAY),
+        )
+        .with_flex_spacer(1.0)
+        .padding((5.0, 3.0))
+        .background(theme::BACKGROUND_LIGHT)
+        .env_scope(|env, data: &SwitcherItem| {
+            if data.selected {
+                env.set(theme::BACKGROUND_LIGHT, env.get(theme::BUTTON_LIGHT));
             }
+        })
+        .on_click(|ctx, data: &mut SwitcherItem, _| ctx.submit_command(SWITCH_TO.with(data.id.clone())))
+}
 
-            _ => ThumbnailState::None,
-        };
+fn build_switcher() -> impl Widget<Chat> {
+    let query = widget::TextBox::new()
+        .with_placeholder("Jump to a room…")
+        .lens(Chat::switcher.then(Switcher::query))
+        .expand_width()
+        .controller(SwitcherController);
+    let results = widget::List::new(create_switcher_item).lens(druid::lens::Map::new(
+        |data: &Chat| data.switcher_results(),
+        |_, _| (),
+    ));
 
-        let contents = match event.content.get("body") {
-            Some(v) => Arc::new(String::from(v.as_string().unwrap().as_str())),
-            None => Arc::new(String::new()),
-        };
+    widget::Flex::column()
+        .with_child(query)
+        .with_spacer(5.0)
+        .with_child(results)
+        .with_flex_spacer(1.0)
+        .fix_width(400.0)
+        .center()
+}
 
-        let edit = match event
-            .content
-            .get("m.relates_to")
-            .and_then(|v| v.get("rel_type"))
-            .and_then(Value::as_string)
-            .map(IString::as_str)
-        {
-            Some(v) if v == "m.replace" => {
-                if let Some(new) = event.content.get("m.new_content") {
-                    let contents =
-                        Arc::new(String::from(new.get("body").unwrap().as_string().unwrap().as_str()));
-                    let formatted =
-                        make_rich_text(new.get("formatted_body"), new.get("body"), true);
-                    Some(Edit {
-                        associated_event_id: Arc::new(String::from(
-                            event
-                                .content
-                                .get("m.relates_to")
-                                .unwrap()
-                                .get("event_id")
-                                .unwrap()
-                                .as_string()
-                                .unwrap()
-                                .as_str(),
-                        )),
-                        contents,
-                        formatted,
-                    })
-                } else {
-                    None
-                }
-            }
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(30);
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+const SYNC_RETRY_MIN: Duration = Duration::from_secs(1);
+const SYNC_RETRY_MAX: Duration = Duration::from_secs(60);
+const TYPING_IDLE: Duration = Duration::from_secs(5);
 
-            _ => None,
+// how long after the last mouse or keyboard input the user still counts as reading along
+const AWAY_AFTER: Duration = Duration::from_secs(60);
+
+struct MessageEntryController {
+    typing_in: Option<Arc<String>>,
+    last_sent: Option<Instant>,
+    idle_timer: TimerToken,
+}
+
+impl MessageEntryController {
+    fn new() -> MessageEntryController {
+        MessageEntryController {
+            typing_in: None,
+            last_sent: None,
+            idle_timer: TimerToken::INVALID,
+        }
+    }
+
+    fn send_typing(data: &Chat, room: Arc<String>, typing: bool) {
+        match data.txs.action_tx.try_send(UserAction::SetTyping(room, typing)) {
+            Ok(_) => (),
+            // the next keystroke sends another one anyway
+            Err(TrySendError::Full(_)) => (),
+            Err(TrySendError::Closed(_)) => panic!("oh no"),
+        }
+    }
+
+    fn start_typing(&mut self, ctx: &mut EventCtx, data: &Chat) {
+        // the server forgets about us after the timeout, so only refresh it before then
+        let stale = match self.last_sent {
+            Some(v) => v.elapsed() > TYPING_TIMEOUT / 2,
+            None => true,
         };
 
-        Message {
-            edit,
-            sender: event.sender.clone(),
-            avatar: AvatarState::Name(event.sender.clone()),
-            event_id: event.event_id.clone(),
-            contents: contents.clone(),
-            formatted,
-            image,
-            editing_message: contents,
-            editing: false,
-            channel: channel.clone(),
-            txs: txs.clone(),
+        if self.typing_in.as_ref() != Some(&data.current_channel) {
+            self.stop_typing(data);
+        }
+
+        if self.typing_in.is_none() || stale {
+            MessageEntryController::send_typing(data, data.current_channel.clone(), true);
+            self.typing_in = Some(data.current_channel.clone());
+            self.last_sent = Some(Instant::now());
         }
+
+        self.idle_timer = ctx.request_timer(TYPING_IDLE);
     }
-}
 
-struct MessageScrollController;
+    fn stop_typing(&mut self, data: &Chat) {
+        if let Some(room) = self.typing_in.take() {
+            MessageEntryController::send_typing(data, room, false);
+        }
+        self.last_sent = None;
+        self.idle_timer = TimerToken::INVALID;
+    }
+}
 
-impl<W> widget::Controller<Chat, widget::Scroll<Chat, W>> for MessageScrollController
-    where W: Widget<Chat>
+impl<W> widget::Controller<Chat, W> for MessageEntryController
+where
+    W: Widget<Chat>,
 {
-    fn event(&mut self, child: &mut widget::Scroll<Chat, W>, ctx: &mut EventCtx, event: &Event, data: &mut Chat, env: &Env) {
-        match event {
-            Event::Wheel(wheel) => {
-                if let Some(channel) = data.channels_hashed.get_mut(&data.current_channel) {
-                    if channel.bottom && wheel.wheel_delta.y < 0.0 {
-                        channel.bottom = false;
-                    }
-                }
-            }
-
-            Event::Command(cmd) if cmd.is(SCROLLED) && data.scroll.is_some() => {
-                data.scroll = None;
-                if let Some(channel) = data.channels_hashed.get_mut(&data.current_channel) {
-                    channel.fetching_old = false;
-                }
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut Chat,
+        env: &Env,
+    ) {
+        let before = data.editing_message.clone();
+        match event {
+            Event::Timer(token) if *token == self.idle_timer => {
+                self.stop_typing(data);
+                ctx.set_handled();
             }
 
-            Event::Command(cmd) if cmd.is(FETCH_FROM_ROOM) => {
-                let (channel, state) = cmd.get_unchecked(FETCH_FROM_ROOM);
-                if let Some(channel) = data.channels_hashed.get_mut(channel) {
-                    channel.prev_batch = state.end.clone();
-                    if channel.first_batch.is_empty() {
-                        channel.first_batch = channel.prev_batch.clone();
+            Event::KeyDown(key) if key.key == Key::Enter && !key.mods.shift() && Moderation::from_line(&data.editing_message).is_some() => {
+                match data.run_command(&data.editing_message) {
+                    Ok(_) => {
+                        data.notice = None;
+                        data.editing_message = Arc::new(String::new());
                     }
-                    channel.top = state.chunk.is_empty();
-                    data.scroll = Some(child.child_size().height);
 
-                    let mut messages = Vector::new();
-                    for m in state
-                        .chunk
-                        .iter()
-                        .map(make_message(channel.id.clone(), data.txs.clone()))
-                    {
-                        match m.edit {
-                            Some(e) => channel.unresolved_edits.push_back(e),
-                            None => messages.push_front(m),
-                        }
-                    }
+                    Err(e) => data.notice = Some(Arc::new(e)),
+                }
+                ctx.set_handled();
+            }
 
-                    messages.extend(channel.messages.clone());
-                    channel.messages = messages;
-                    let mut resolved = vec![];
-                    for (i, edit) in channel.unresolved_edits.iter().enumerate() {
-                        for msg in channel.messages.iter_mut() {
-                            if msg.event_id == edit.associated_event_id {
-                                msg.contents = edit.contents.clone();
-                                msg.formatted = edit.formatted.clone();
-                                resolved.push(i);
-                                break;
-                            }
+            Event::KeyDown(key) if key.key == Key::Enter && !key.mods.shift() => {
+                data.notice = None;
+                if !data.editing_message.is_empty() {
+                    // TODO: do this based on current cursor position
+                    let count = data.editing_message.match_indices("```").count();
+                    if count % 2 == 0 {
+                        let reply = data
+                            .replying_to
+                            .take()
+                            .filter(|v| v.channel == data.current_channel)
+                            .map(|v| ReplyTo {
+                                event_id: v.event_id,
+                                sender: v.sender,
+                                body: v.contents,
+                                formatted: v.html,
+                            });
+                        // a doubled slash is how to send a line that would otherwise be a command
+                        let body = match data.editing_message.strip_prefix("//") {
+                            Some(rest) => Arc::new(format!("/{}", rest)),
+                            None => data.editing_message.clone(),
+                        };
+                        let message = Message::local_echo(
+                            data.current_channel.clone(),
+                            data.user_id.clone(),
+                            body,
+                            reply,
+                            data.txs.clone(),
+                        );
+                        message.send();
+                        if let Some(channel) = data.channels_hashed.get_mut(&data.current_channel) {
+                            channel.bottom = true;
+                            channel.push_message(message);
                         }
+                        data.editing_message = Arc::new(String::new());
+                        ctx.set_handled();
                     }
-
-                    for (i, resolved) in resolved.into_iter().enumerate() {
-                        channel.unresolved_edits.remove(resolved - i);
-                    }
+                } else {
+                    ctx.set_handled();
                 }
             }
 
             _ => (),
         }
-
         child.event(ctx, event, data, env);
 
-        if let Some(channel) = data.channels_hashed.get_mut(&data.current_channel) {
-            if !channel.bottom && child.viewport_rect().contains(Point {
-                x: 0.0,
-                y: child.child_size().height - 0.01,
-            }) {
-                channel.bottom = true;
-            }
-
-            if !channel.fetching_old && !channel.top && (child.viewport_rect().contains(Point {
-                x: 0.0,
-                y: 0.0,
-            }) || child.child_size().height == 0.0) {
-                match data.txs.sync_tx.try_send(Syncing::FetchFromRoom(channel.id.clone(), channel.prev_batch.clone(), Arc::new(json!({
-                    "limit": 50,
-                    "types": [
-                        "m.room.message"
-                    ]
-                }).to_string()))) {
-                    Ok(_) => (),
-                    Err(TrySendError::Full(_)) => panic!("oh no"),
-                    Err(TrySendError::Closed(_)) => panic!("aaaaa"),
-                }
-
-                channel.fetching_old = true;
+        if before != data.editing_message {
+            if data.editing_message.is_empty() {
+                self.stop_typing(data);
+            } else {
+                self.start_typing(ctx, data);
             }
         }
     }
 
     fn lifecycle(
         &mut self,
-        child: &mut widget::Scroll<Chat, W>,
+        child: &mut W,
         ctx: &mut druid::LifeCycleCtx,
         event: &druid::LifeCycle,
         data: &Chat,
         env: &Env,
     ) {
-        child.lifecycle(ctx, event, data, env);
-        if let Some(channel) = data.channels_hashed.get(&data.current_channel) {
-            if channel.bottom {
-                child.scroll_to_on_axis(Axis::Vertical, f64::INFINITY);
-            } else if let Some(scroll) = data.scroll {
-                if (scroll - child.child_size().height).abs() > 0.001 {
-                    child.scroll_to_on_axis(Axis::Vertical, child.child_size().height - scroll);
-                    ctx.submit_command(SCROLLED);
-                }
-            }
+        if let druid::LifeCycle::FocusChanged(false) = event {
+            self.stop_typing(data);
         }
-    }
-
-    fn update(&mut self, child: &mut widget::Scroll<Chat, W>, ctx: &mut druid::UpdateCtx, old_data: &Chat, data: &Chat, env: &Env) {
-        child.update(ctx, old_data, data, env);
+        child.lifecycle(ctx, event, data, env);
     }
 }
 
-struct ChatController;
+struct EditEntryController;
 
-impl<W> widget::Controller<Chat, W> for ChatController
+impl<W> widget::Controller<Message, W> for EditEntryController
 where
-    W: widget::Widget<Chat>,
+    W: Widget<Message>,
 {
     fn event(
         &mut self,
         child: &mut W,
         ctx: &mut EventCtx,
         event: &Event,
-        data: &mut Chat,
+        data: &mut Message,
         env: &Env,
     ) {
         match event {
-            Event::WindowConnected => {
-                match data.txs.sync_tx.try_send(Syncing::ClientSync(
-                    Arc::new(String::new()),
-                    Arc::new(json!({
-                        "presence": {
-                            "limit": 0,
-                        },
-                        "room": {
-                            "ephemeral": {
-                                "limit": 0,
-                            },
-                            "state": {
-                                "limit": 0,
-                            },
-                            "timeline": {
-                                "limit": 0,
-                            },
-                        },
-                    }).to_string()),
-                )) {
-                    Ok(_) => (),
-                    Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
-                    Err(TrySendError::Closed(_)) => panic!("oh no"),
-                }
-            }
-
-            Event::Command(cmd) if cmd.is(SYNC_FAIL) => {
-                // TODO: something smarter than this
-                match data.txs.sync_tx.try_send(Syncing::ClientSync(
-                    Arc::new(String::new()),
-                    Arc::new(json!({
-                        "room": {
-                            "timeline": {
-                                "limit": 50,
-                                "types": [
-                                    "m.room.message"
-                                ]
-                            }
+            Event::KeyDown(key) if key.key == Key::Enter && !key.mods.shift() => {
+                if !data.editing_message.is_empty() {
+                    // TODO: do this based on current cursor position
+                    let count = data.editing_message.match_indices("```").count();
+                    if count % 2 == 0 {
+                        let formatted = markdown::parse_markdown(&*data.editing_message);
+                        let formatted = markdown::markdown_to_html(formatted);
+                        match data.txs.action_tx.try_send(UserAction::EditMessage(
+                            data.channel.clone(),
+                            chat::new_transaction_id(),
+                            data.event_id.clone(),
+                            data.editing_message.clone(),
+                            Arc::new(formatted),
+                        )) {
+                            Ok(_) => (),
+                            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+                            Err(TrySendError::Closed(_)) => panic!("oh no"),
                         }
-                    })
-                    .to_string()),
-                )) {
-                    Ok(_) => (),
-                    Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
-                    Err(TrySendError::Closed(_)) => panic!("oh no"),
+                        data.editing_message = Arc::new(String::new());
+                        data.editing = false;
+                        ctx.set_handled();
+                    }
+                } else {
+                    ctx.set_handled();
                 }
             }
 
-            Event::Command(cmd) if cmd.is(SYNC) => {
-                let sync = cmd.get_unchecked(SYNC);
-                if let Some(rooms) = &sync.rooms {
-                    if let Some(join) = &rooms.join {
-                        for (id, joined) in join.iter() {
-                            let (mut messages, mut edits) = (Vector::new(), Vector::new());
-                            for m in joined
-                                .timeline
-                                .events
-                                .iter()
-                                .map(make_message(id.clone(), data.txs.clone()))
-                            {
-                                match m.edit {
-                                    Some(e) => edits.push_back(e),
-                                    None => messages.push_back(m),
-                                }
-                            }
+            _ => (),
+        }
+        child.event(ctx, event, data, env)
+    }
+}
 
-                            if let Some(channel) = data.channels_hashed.get_mut(id) {
-                                channel.messages.extend(messages);
-                            } else {
-                                data.channels_hashed.insert(
-                                    id.clone(),
-                                    Channel {
-                                        id: id.clone(),
-                                        name: match &joined.name {
-                                            Some(v) => v.clone(),
-                                            None => Arc::new(String::from("<unnamed room>")),
-                                        },
-                                        messages,
-                                        unresolved_edits: Vector::new(),
-                                        prev_batch: Arc::new(String::new()),
-                                        first_batch: Arc::new(String::new()),
-                                        bottom: true,
-                                        fetching_old: false,
-                                        top: false,
-                                    },
-                                );
-                                data.channels.push_back(id.clone());
-                            }
-                            if let Some(channel) = data.channels_hashed.get_mut(id) {
-                                let mut resolved = vec![];
-                                for (i, edit) in edits.iter().enumerate() {
-                                    for msg in channel.messages.iter_mut() {
-                                        if msg.event_id == edit.associated_event_id {
-                                            msg.contents = edit.contents.clone();
-                                            msg.formatted = edit.formatted.clone();
-                                            resolved.push(i);
-                                            break;
-                                        }
-                                    }
-                                }
+// `range` and the returned cursor count characters, the same as the mask that's drawn
+fn splice_password(password: &str, range: Range<usize>, text: &str) -> (String, usize) {
+    let cursor = range.start + text.chars().count();
+    let password = password
+        .chars()
+        .take(range.start)
+        .chain(text.chars())
+        .chain(password.chars().skip(range.end))
+        .collect();
+    (password, cursor)
+}
 
-                                for (i, resolved) in resolved.into_iter().enumerate() {
-                                    edits.remove(resolved - i);
-                                }
+// edits the password itself and only puts the mask in its place when drawing
+struct PasswordBox {
+    cursor: usize,
+    layout: TextLayout<String>,
+    placeholder: TextLayout<String>,
+}
 
-                                channel.unresolved_edits = edits;
-                            }
-                        }
-                    }
-                }
+impl PasswordBox {
+    fn new() -> PasswordBox {
+        let mut placeholder = TextLayout::from_text("Password");
+        placeholder.set_text_color(Color::GRAY);
+        PasswordBox {
+            cursor: 0,
+            layout: TextLayout::new(),
+            placeholder,
+        }
+    }
 
-                match data.txs.sync_tx.try_send(Syncing::ClientSync(
-                    sync.next_batch.clone(),
-                    Arc::new(json!({
-                        "room": {
-                            "timeline": {
-                                "limit": 50,
-                                "types": [
-                                    "m.room.message"
-                                ]
-                            }
-                        }
-                    })
-                    .to_string()),
-                )) {
-                    Ok(_) => (),
-                    Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
-                    Err(TrySendError::Closed(_)) => panic!("oh no"),
-                }
+    fn splice(&mut self, data: &mut Login, range: Range<usize>, text: &str) {
+        // a pasted line break would only ever be a mistake
+        let text: String = text.chars().filter(|v| *v != '\n' && *v != '\r').collect();
+        let (password, cursor) = splice_password(&data.password, range, &text);
+        data.password = Arc::new(password);
+        self.cursor = cursor;
+    }
+
+    fn paste(&mut self, data: &mut Login) {
+        if let Some(text) = Application::global().clipboard().get_string() {
+            let cursor = self.cursor;
+            self.splice(data, cursor..cursor, &text);
+        }
+    }
+}
+
+impl Widget<Login> for PasswordBox {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut Login, _env: &Env) {
+        let len = data.password.chars().count();
+        let cursor = self.cursor.min(len);
+        match event {
+            Event::MouseDown(mouse) => {
+                ctx.request_focus();
+                self.cursor = self.layout.text_position_for_point(mouse.pos - PASSWORD_PADDING).min(len);
+                ctx.request_paint();
             }
 
-            Event::Command(cmd) if cmd.is(LINK) => {
-                let link = cmd.get_unchecked(LINK);
-                if open::that(&**link).is_err() {
-                    eprintln!("error opening link {}", link);
+            Event::KeyDown(key) if ctx.is_focused() => {
+                let shortcut = key.mods.ctrl() || key.mods.meta();
+                match &key.key {
+                    Key::Enter => data.submit(),
+                    Key::Tab if key.mods.shift() => ctx.focus_prev(),
+                    Key::Tab => ctx.focus_next(),
+                    Key::Backspace if cursor > 0 => self.splice(data, cursor - 1..cursor, ""),
+                    Key::Delete if cursor < len => self.splice(data, cursor..cursor + 1, ""),
+                    Key::ArrowLeft => self.cursor = cursor.saturating_sub(1),
+                    Key::ArrowRight => self.cursor = (cursor + 1).min(len),
+                    Key::Home => self.cursor = 0,
+                    Key::End => self.cursor = len,
+                    Key::Character(c) if shortcut && c.eq_ignore_ascii_case("v") => self.paste(data),
+                    Key::Character(c) if !shortcut => self.splice(data, cursor..cursor, c),
+                    _ => (),
                 }
+                ctx.request_paint();
+                ctx.set_handled();
             }
 
-            Event::WindowDisconnected => {
-                while let Err(TrySendError::Full(_)) = data.txs.sync_tx.try_send(Syncing::Quit) {}
-                while let Err(TrySendError::Full(_)) = data.txs.action_tx.try_send(UserAction::Quit) {}
-                while let Err(TrySendError::Full(_)) = data.txs.media_tx.try_send(MediaFetch::Quit) {}
+            Event::Command(cmd) if cmd.is(commands::PASTE) && ctx.is_focused() => {
+                self.paste(data);
+                ctx.request_paint();
+                ctx.set_handled();
             }
 
             _ => (),
         }
+    }
 
-        child.event(ctx, event, data, env)
+    fn lifecycle(&mut self, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &Login, _env: &Env) {
+        match event {
+            LifeCycle::WidgetAdded => {
+                ctx.register_for_focus();
+                self.layout.set_text(PASSWORD_MASK.to_string().repeat(data.password.chars().count()));
+            }
+
+            LifeCycle::FocusChanged(_) => ctx.request_paint(),
+
+            _ => (),
+        }
+    }
+
+    fn update(&mut self, ctx: &mut UpdateCtx, old_data: &Login, data: &Login, _env: &Env) {
+        if !old_data.password.same(&data.password) {
+            let len = data.password.chars().count();
+            self.layout.set_text(PASSWORD_MASK.to_string().repeat(len));
+            self.cursor = self.cursor.min(len);
+            ctx.request_layout();
+        }
+    }
+
+    fn layout(&mut self, ctx: &mut LayoutCtx, bc: &BoxConstraints, _data: &Login, env: &Env) -> Size {
+        self.layout.rebuild_if_needed(ctx.text(), env);
+        self.placeholder.rebuild_if_needed(ctx.text(), env);
+
+        let width = if bc.is_width_bounded() {
+            bc.max().width
+        } else {
+            env.get(theme::WIDE_WIDGET_WIDTH)
+        };
+        let height = (self.placeholder.size().height + PASSWORD_PADDING.y * 2.0).max(env.get(theme::BORDERED_WIDGET_HEIGHT));
+        bc.constrain(Size::new(width, height))
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, data: &Login, env: &Env) {
+        let border_width = env.get(theme::TEXTBOX_BORDER_WIDTH);
+        let rect = ctx
+            .size()
+            .to_rect()
+            .inset(-border_width / 2.0)
+            .to_rounded_rect(env.get(theme::TEXTBOX_BORDER_RADIUS));
+        let border = if ctx.is_focused() {
+            env.get(theme::PRIMARY_LIGHT)
+        } else {
+            env.get(theme::BORDER_DARK)
+        };
+        ctx.fill(rect, &env.get(theme::BACKGROUND_LIGHT));
+
+        let origin = PASSWORD_PADDING.to_point();
+        let focused = ctx.is_focused();
+        let cursor = self.cursor.min(data.password.chars().count());
+        ctx.with_save(|ctx| {
+            ctx.clip(rect);
+            if data.password.is_empty() && !focused {
+                self.placeholder.draw(ctx, origin);
+            } else {
+                self.layout.draw(ctx, origin);
+            }
+
+            if focused {
+                let line = self.layout.cursor_line_for_text_position(cursor) + PASSWORD_PADDING;
+                ctx.stroke(line, &env.get(theme::CURSOR_COLOR), 1.0);
+            }
+        });
+        ctx.stroke(rect, &border, border_width);
+    }
+}
+
+fn build_login() -> impl Widget<Login> {
+    let user = widget::TextBox::new()
+        .with_placeholder("@user:example.org")
+        .lens(Login::user)
+        .expand_width();
+    let password = PasswordBox::new().expand_width();
+    let homeserver = widget::TextBox::new()
+        .with_placeholder("Homeserver name or URL (optional)")
+        .lens(Login::homeserver)
+        .expand_width();
+    let error = widget::Label::dynamic(|data: &Login, _| (*data.error).clone())
+        .with_text_color(Color::rgb8(0xe0, 0x40, 0x40))
+        .with_line_break_mode(LineBreaking::WordWrap);
+    let button = widget::Either::new(
+        |data: &Login, _| data.logging_in,
+        widget::Spinner::new(),
+        widget::Button::new("Log in").on_click(|_, data: &mut Login, _| data.submit()),
+    );
+
+    widget::Flex::column()
+        .with_child(widget::Label::new("Log in to Matrix").with_text_size(24.0))
+        .with_spacer(10.0)
+        .with_child(user)
+        .with_spacer(5.0)
+        .with_child(password)
+        .with_spacer(5.0)
+        .with_child(homeserver)
+        .with_spacer(10.0)
+        .with_child(button)
+        .with_spacer(5.0)
+        .with_child(error)
+        .fix_width(300.0)
+        .center()
+}
+
+fn create_badge(color: Color) -> impl Widget<(Arc<String>, Channel)> {
+    widget::Label::dynamic(|data: &(Arc<String>, Channel), _| match data.1.notification_count {
+        0 => String::from("@"),
+        n if n > 99 => String::from("99+"),
+        n => n.to_string(),
+    })
+    .with_text_size(11.0)
+    .with_text_color(Color::WHITE)
+    .padding((5.0, 1.0))
+    .background(color)
+    .rounded(8.0)
+}
+
+struct ChannelEntryController;
+
+impl<W> widget::Controller<(Arc<String>, Channel), W> for ChannelEntryController
+where
+    W: Widget<(Arc<String>, Channel)>,
+{
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut (Arc<String>, Channel),
+        env: &Env,
+    ) {
+        match event {
+            Event::MouseDown(mouse) if mouse.button.is_right() => {
+                ctx.show_context_menu(create_channel_menu(&data.1), mouse.window_pos);
+                ctx.set_handled();
+            }
+
+            _ => child.event(ctx, event, data, env),
+        }
+    }
+}
+
+struct SenderController;
+
+impl<W> widget::Controller<Message, W> for SenderController
+where
+    W: Widget<Message>,
+{
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut Message,
+        env: &Env,
+    ) {
+        match event {
+            Event::MouseDown(mouse) if mouse.button.is_right() => {
+                ctx.show_context_menu(create_member_menu(data.channel.clone(), data.sender.clone()), mouse.window_pos);
+                ctx.set_handled();
+            }
+
+            _ => child.event(ctx, event, data, env),
+        }
+    }
+}
+
+// these fill in the composer so there's a chance to add a reason before anything happens
+fn create_member_menu(room: Arc<String>, user: Arc<String>) -> Menu<Chat> {
+    let mut menu = Menu::new("");
+    for &(label, action) in [
+        ("Invite…", Moderation::Invite),
+        ("Kick…", Moderation::Kick),
+        ("Ban…", Moderation::Ban),
+        ("Unban…", Moderation::Unban),
+    ]
+    .iter()
+    {
+        let (room, target) = (room.clone(), user.clone());
+        let command = format!("/{} {} ", action.command(), user);
+        menu = menu.entry(
+            MenuItem::new(label)
+                .enabled_if(move |data: &Chat, _| data.can_moderate(&room, action, &target))
+                .on_activate(move |_, data: &mut Chat, _| data.editing_message = Arc::new(command.clone())),
+        );
+    }
+
+    menu
+}
+
+fn create_channel_menu(channel: &Channel) -> Menu<Chat> {
+    let room = channel.id.clone();
+    let favourite = channel.has_tag(FAVOURITE);
+    let low_priority = channel.has_tag(LOW_PRIORITY);
+
+    let mut menu = Menu::new("")
+        .entry({
+            let room = room.clone();
+            MenuItem::new(if favourite { "Remove from favourites" } else { "Add to favourites" })
+                .on_activate(move |_, data: &mut Chat, _| {
+                    data.set_tag(&room, FAVOURITE, !favourite);
+                    if !favourite {
+                        data.set_tag(&room, LOW_PRIORITY, false);
+                    }
+                })
+        })
+        .entry({
+            let room = room.clone();
+            MenuItem::new(if low_priority { "Remove from low priority" } else { "Mark as low priority" })
+                .on_activate(move |_, data: &mut Chat, _| {
+                    data.set_tag(&room, LOW_PRIORITY, !low_priority);
+                    if !low_priority {
+                        data.set_tag(&room, FAVOURITE, false);
+                    }
+                })
+        })
+        .separator();
+
+    for tag in channel.tags.keys().filter(|v| v.starts_with("u.")) {
+        let room = room.clone();
+        let tag = tag.clone();
+        menu = menu.entry(
+            MenuItem::new(format!("Remove tag \"{}\"", &tag[2..]))
+                .on_activate(move |_, data: &mut Chat, _| data.set_tag(&room, &tag, false)),
+        );
     }
+
+    menu.entry({
+        let room = room.clone();
+        MenuItem::new("New tag…").on_activate(move |_, data: &mut Chat, _| {
+            data.prompt = Some(Prompt::Tag(room.clone()));
+            data.prompt_text = Arc::new(String::new());
+        })
+    })
+    .separator()
+    .entry({
+        let room = room.clone();
+        MenuItem::new("Settings…").on_activate(move |_, data: &mut Chat, _| data.open_settings(room.clone()))
+    })
+    .entry(MenuItem::new("Leave").on_activate(move |_, data: &mut Chat, _| data.leave(room.clone())))
+}
+
+#[derive(Data, Clone, PartialEq)]
+enum Prompt {
+    Tag(Arc<String>),
+    StartDm,
+    Join,
 }
 
-struct MessageEntryController;
+struct PromptController;
 
-impl<W> widget::Controller<Chat, W> for MessageEntryController
+impl<W> widget::Controller<Chat, W> for PromptController
 where
     W: Widget<Chat>,
 {
@@ -774,28 +3473,37 @@ where
         env: &Env,
     ) {
         match event {
-            Event::KeyDown(key) if key.key == Key::Enter && !key.mods.shift() => {
-                if !data.editing_message.is_empty() {
-                    // TODO: do this based on current cursor position
-                    let count = data.editing_message.match_indices("```").count();
-                    if count % 2 == 0 {
-                        let formatted = markdown::parse_markdown(&*data.editing_message);
-                        let formatted = markdown::markdown_to_html(formatted);
-                        match data.txs.action_tx.try_send(UserAction::SendMessage(
-                            data.current_channel.clone(),
-                            data.editing_message.clone(),
-                            Arc::new(formatted),
-                        )) {
-                            Ok(_) => (),
-                            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
-                            Err(TrySendError::Closed(_)) => panic!("oh no"),
-                        }
-                        data.editing_message = Arc::new(String::new());
-                        ctx.set_handled();
+            Event::KeyDown(key) if key.key == Key::Enter => {
+                let text = String::from(data.prompt_text.trim());
+                match data.prompt.take() {
+                    _ if text.is_empty() => (),
+
+                    Some(Prompt::Tag(room)) => {
+                        let tag = if text.starts_with("u.") {
+                            text
+                        } else {
+                            format!("u.{}", text)
+                        };
+                        data.set_tag(&room, &tag, true);
                     }
-                } else {
-                    ctx.set_handled();
+
+                    Some(Prompt::StartDm) => data.start_dm(Arc::new(text)),
+
+                    Some(Prompt::Join) => {
+                        // the server part of an id or alias is the best guess we have for where to join through
+                        let via = server_name(&text).map(|v| Arc::new(String::from(v))).into_iter().collect();
+                        data.join(Arc::new(text), via);
+                    }
+
+                    None => (),
                 }
+                data.prompt = None;
+                ctx.set_handled();
+            }
+
+            Event::KeyDown(key) if key.key == Key::Escape => {
+                data.prompt = None;
+                ctx.set_handled();
             }
 
             _ => (),
@@ -804,56 +3512,321 @@ where
     }
 }
 
-struct EditEntryController;
+struct SpaceEntryController;
 
-impl<W> widget::Controller<Message, W> for EditEntryController
+impl<W> widget::Controller<SpaceEntry, W> for SpaceEntryController
 where
-    W: Widget<Message>,
+    W: Widget<SpaceEntry>,
 {
     fn event(
         &mut self,
         child: &mut W,
         ctx: &mut EventCtx,
         event: &Event,
-        data: &mut Message,
+        data: &mut SpaceEntry,
         env: &Env,
     ) {
         match event {
-            Event::KeyDown(key) if key.key == Key::Enter && !key.mods.shift() => {
-                if !data.editing_message.is_empty() {
-                    // TODO: do this based on current cursor position
-                    let count = data.editing_message.match_indices("```").count();
-                    if count % 2 == 0 {
-                        let formatted = markdown::parse_markdown(&*data.editing_message);
-                        let formatted = markdown::markdown_to_html(formatted);
-                        match data.txs.action_tx.try_send(UserAction::EditMessage(
-                            data.channel.clone(),
-                            data.event_id.clone(),
-                            data.editing_message.clone(),
-                            Arc::new(formatted),
-                        )) {
-                            Ok(_) => (),
-                            Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
-                            Err(TrySendError::Closed(_)) => panic!("oh no"),
-                        }
-                        data.editing_message = Arc::new(String::new());
-                        data.editing = false;
-                        ctx.set_handled();
-                    }
-                } else {
-                    ctx.set_handled();
-                }
+            Event::MouseDown(mouse) if mouse.button.is_right() => {
+                let space = data.id.clone();
+                let menu = Menu::new("").entry(
+                    MenuItem::new("Leave space").on_activate(move |_, data: &mut Chat, _| data.leave(space.clone())),
+                );
+                ctx.show_context_menu(menu, mouse.window_pos);
+                ctx.set_handled();
             }
 
-            _ => (),
+            _ => child.event(ctx, event, data, env),
         }
-        child.event(ctx, event, data, env)
     }
 }
 
+fn create_rail_button<T: Data>(label: impl Into<widget::LabelText<T>>) -> impl Widget<T> {
+    widget::Label::new(label)
+        .center()
+        .fix_size(40.0, 40.0)
+        .background(theme::BACKGROUND_LIGHT)
+        .rounded(8.0)
+        .padding((0.0, 2.0))
+}
+
+fn create_space_entry() -> impl Widget<SpaceEntry> {
+    create_rail_button(|data: &SpaceEntry, _: &Env| {
+        data.name
+            .trim_start_matches(|c: char| !c.is_alphanumeric())
+            .chars()
+            .take(2)
+            .collect::<String>()
+    })
+    .env_scope(|env, data: &SpaceEntry| {
+        if data.selected {
+            env.set(theme::BACKGROUND_LIGHT, env.get(theme::BUTTON_LIGHT));
+        }
+    })
+    .on_click(|ctx, data: &mut SpaceEntry, _| ctx.submit_command(SELECT_SPACE.with(Some(data.id.clone()))))
+    .controller(SpaceEntryController)
+}
+
+fn build_rail() -> impl Widget<Chat> {
+    let home = create_rail_button("Home")
+        .env_scope(|env, data: &Chat| {
+            if data.selected_space.is_none() {
+                env.set(theme::BACKGROUND_LIGHT, env.get(theme::BUTTON_LIGHT));
+            }
+        })
+        .on_click(|ctx, _: &mut Chat, _| ctx.submit_command(SELECT_SPACE.with(None)));
+    let spaces = widget::List::new(create_space_entry).lens(druid::lens::Map::new(
+        |data: &Chat| data.rail_spaces(),
+        |_, _| (),
+    ));
+    let join = create_rail_button("+").on_click(|_, data: &mut Chat, _| {
+        data.prompt = Some(Prompt::Join);
+        data.prompt_text = Arc::new(String::new());
+    });
+
+    widget::Flex::column()
+        .with_child(home)
+        .with_flex_child(widget::Scroll::new(spaces).vertical(), 1.0)
+        .with_child(join)
+        .padding((0.0, 0.0, 5.0, 0.0))
+}
+
+fn create_level_entry() -> impl Widget<LevelEntry> {
+    widget::Flex::row()
+        .with_flex_child(widget::TextBox::new().lens(LevelEntry::key).expand_width(), 3.0)
+        .with_spacer(4.0)
+        .with_flex_child(widget::TextBox::new().with_placeholder("default").lens(LevelEntry::level).expand_width(), 1.0)
+        .padding((0.0, 1.0))
+}
+
+fn create_levels(title: &str, add: &'static str, lens: impl Lens<RoomSettings, Vector<LevelEntry>> + Clone + 'static) -> impl Widget<RoomSettings> {
+    let push = lens.clone();
+    widget::Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(widget::Label::new(title).with_text_size(12.0))
+        .with_child(widget::List::new(create_level_entry).lens(lens))
+        .with_child(widget::Button::new(add).on_click(move |_, data: &mut RoomSettings, _| {
+            push.with_mut(data, |entries| {
+                entries.push_back(LevelEntry {
+                    key: Arc::new(String::new()),
+                    level: Arc::new(String::new()),
+                })
+            });
+        }))
+        .padding((0.0, 0.0, 0.0, 6.0))
+        .disabled_if(|data: &RoomSettings, _| !data.can("m.room.power_levels"))
+}
+
+fn create_choice(label: &'static str, event_type: &'static str, options: &'static [&'static str], lens: impl Lens<RoomSettings, Arc<String>> + Clone + 'static) -> impl Widget<RoomSettings> {
+    let get = lens.clone();
+    widget::Button::dynamic(move |data: &RoomSettings, _| format!("{}: {}", label, get.get(data).replace('_', " ")))
+        .on_click(move |_, data: &mut RoomSettings, _| lens.with_mut(data, |v| *v = next_option(options, v)))
+        .disabled_if(move |data: &RoomSettings, _| !data.can(event_type))
+        .padding((0.0, 0.0, 0.0, 6.0))
+}
+
+fn build_settings() -> impl Widget<RoomSettings> {
+    let error = widget::Label::dynamic(|data: &RoomSettings, _| match &data.error {
+        Some(v) => (**v).clone(),
+        None => String::new(),
+    })
+    .with_text_color(Color::rgb8(0xff, 0x60, 0x60))
+    .with_line_break_mode(LineBreaking::WordWrap);
+    let buttons = widget::Flex::row()
+        .with_child(
+            widget::Button::new("Save")
+                .on_click(|ctx, _, _| ctx.submit_command(SAVE_SETTINGS))
+                .disabled_if(|data: &RoomSettings, _| data.loading),
+        )
+        .with_spacer(4.0)
+        .with_child(widget::Button::new("Close").on_click(|ctx, _, _| ctx.submit_command(CLOSE_SETTINGS)));
+
+    widget::Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(
+            widget::Label::dynamic(|data: &RoomSettings, _| {
+                if data.loading && data.error.is_none() {
+                    String::from("Room settings (loading…)")
+                } else {
+                    String::from("Room settings")
+                }
+            })
+            .with_text_size(18.0)
+            .padding((0.0, 0.0, 0.0, 8.0)),
+        )
+        .with_child(create_settings_field("Name", "m.room.name", RoomSettings::name))
+        .with_child(create_settings_field("Topic", "m.room.topic", RoomSettings::topic))
+        .with_child(create_settings_field("Avatar (mxc:// URL)", "m.room.avatar", RoomSettings::avatar))
+        .with_child(create_choice("Who can join", "m.room.join_rules", &JOIN_RULES, RoomSettings::join_rule))
+        .with_child(create_choice("Who can read history", "m.room.history_visibility", &HISTORY_VISIBILITY, RoomSettings::history_visibility))
+        .with_child(create_choice("Guests", "m.room.guest_access", &GUEST_ACCESS, RoomSettings::guest_access))
+        .with_child(create_levels("User power levels", "Add user", RoomSettings::users))
+        .with_child(create_levels("Power needed to send events", "Add event type", RoomSettings::events))
+        .with_child(error)
+        .with_child(buttons)
+        .padding(10.0)
+}
+
+fn create_settings_field(label: &str, event_type: &'static str, lens: impl Lens<RoomSettings, Arc<String>> + 'static) -> impl Widget<RoomSettings> {
+    widget::Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(widget::Label::new(label).with_text_size(12.0))
+        .with_child(widget::TextBox::new().lens(lens).expand_width())
+        .padding((0.0, 0.0, 0.0, 6.0))
+        .disabled_if(move |data: &RoomSettings, _| !data.can(event_type))
+}
+
+fn create_field(label: &str, placeholder: &str, lens: impl Lens<NewRoom, Arc<String>> + 'static) -> impl Widget<NewRoom> {
+    widget::Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(widget::Label::new(label).with_text_size(12.0))
+        .with_child(widget::TextBox::new().with_placeholder(placeholder).lens(lens).expand_width())
+        .padding((0.0, 0.0, 0.0, 6.0))
+}
+
+fn build_new_room() -> impl Widget<NewRoom> {
+    let preset = widget::Button::dynamic(|data: &NewRoom, _| match data.preset {
+        chat::RoomPreset::Private => String::from("Private: invite only"),
+        chat::RoomPreset::TrustedPrivate => String::from("Private: everyone invited is an admin"),
+        chat::RoomPreset::Public => String::from("Public: anyone can join"),
+    })
+    .on_click(|_, data: &mut NewRoom, _| {
+        data.preset = match data.preset {
+            chat::RoomPreset::Private => chat::RoomPreset::TrustedPrivate,
+            chat::RoomPreset::TrustedPrivate => chat::RoomPreset::Public,
+            chat::RoomPreset::Public => chat::RoomPreset::Private,
+        };
+    });
+    let error = widget::Label::dynamic(|data: &NewRoom, _| match &data.error {
+        Some(v) => (**v).clone(),
+        None => String::new(),
+    })
+    .with_text_color(Color::rgb8(0xff, 0x60, 0x60))
+    .with_line_break_mode(LineBreaking::WordWrap);
+    let buttons = widget::Flex::row()
+        .with_child(
+            widget::Button::dynamic(|data: &NewRoom, _| String::from(if data.creating { "Creating…" } else { "Create" }))
+                .on_click(|ctx, _, _| ctx.submit_command(CREATE_ROOM))
+                .disabled_if(|data: &NewRoom, _| data.creating),
+        )
+        .with_spacer(4.0)
+        .with_child(widget::Button::new("Cancel").on_click(|ctx, _, _| ctx.submit_command(CANCEL_NEW_ROOM)));
+
+    widget::Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(widget::Label::new("New room").with_text_size(18.0).padding((0.0, 0.0, 0.0, 8.0)))
+        .with_child(create_field("Name", "uwutalk fans", NewRoom::name))
+        .with_child(create_field("Topic", "", NewRoom::topic))
+        .with_child(create_field("Address", "uwutalk-fans", NewRoom::alias))
+        .with_child(create_field("Invite", "@alice:example.org, @bob:example.org", NewRoom::invite))
+        .with_child(preset)
+        .with_spacer(6.0)
+        .with_child(widget::Checkbox::new("End-to-end encryption").lens(NewRoom::encrypted))
+        .with_spacer(4.0)
+        .with_child(widget::Checkbox::new("This is a space").lens(NewRoom::space))
+        .with_spacer(8.0)
+        .with_child(error)
+        .with_child(buttons)
+        .padding(10.0)
+}
+
+fn create_invite() -> impl Widget<Invite> {
+    let name = widget::Label::dynamic(|data: &Invite, _| (*data.name).clone())
+        .with_line_break_mode(LineBreaking::WordWrap);
+    let inviter = widget::Label::dynamic(|data: &Invite, _| {
+        if data.is_direct {
+            format!("wants to chat with you ({})", data.inviter)
+        } else {
+            format!("invited by {}", data.inviter)
+        }
+    })
+    .with_text_size(12.0)
+    .with_text_color(Color::GRAY)
+    .with_line_break_mode(LineBreaking::WordWrap);
+    let buttons = widget::Flex::row()
+        .with_child(widget::Button::new("Accept").on_click(|ctx, data: &mut Invite, _| {
+            data.pending = true;
+            ctx.submit_command(ACCEPT_INVITE.with(data.room_id.clone()));
+        }))
+        .with_spacer(4.0)
+        .with_child(widget::Button::new("Decline").on_click(|ctx, data: &mut Invite, _| {
+            data.pending = true;
+            ctx.submit_command(DECLINE_INVITE.with(data.room_id.clone()));
+        }))
+        .disabled_if(|data: &Invite, _| data.pending);
+
+    widget::Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(name)
+        .with_child(inviter)
+        .with_child(buttons)
+        .padding((5.0, 2.0))
+}
+
+fn build_invites() -> impl Widget<Chat> {
+    widget::Either::new(
+        |data: &Chat, _| !data.invites.is_empty(),
+        widget::Flex::column()
+            .cross_axis_alignment(CrossAxisAlignment::Start)
+            .with_child(
+                widget::Label::new("Invites")
+                    .with_text_size(12.0)
+                    .with_text_color(Color::GRAY)
+                    .padding((5.0, 6.0, 5.0, 2.0)),
+            )
+            .with_child(widget::List::new(create_invite).lens(Chat::invites)),
+        widget::SizedBox::empty(),
+    )
+}
+
+fn create_channel_section() -> impl Widget<ChannelSection> {
+    widget::Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(
+            widget::Label::dynamic(|data: &ChannelSection, _| (*data.title).clone())
+                .with_text_size(12.0)
+                .with_text_color(Color::GRAY)
+                .padding((5.0, 6.0, 5.0, 2.0)),
+        )
+        .with_child(widget::List::new(create_channel_listing))
+}
+
 fn create_channel_listing() -> impl Widget<(Arc<String>, Channel)> {
-    widget::Button::dynamic(|data: &(Arc<String>, Channel), _| (*data.1.name).clone())
+    let avatar = widget::Maybe::or_empty(|| create_avatar(20.0).padding((0.0, 0.0, 4.0, 0.0))).lens(druid::lens::Map::new(
+        |data: &(Arc<String>, Channel)| data.1.dm.clone(),
+        |data: &mut (Arc<String>, Channel), dm| data.1.dm = dm,
+    ));
+    let name = widget::Label::dynamic(|data: &(Arc<String>, Channel), _| (*data.1.display_name()).clone())
+        .with_line_break_mode(LineBreaking::Clip)
+        .env_scope(|env, data: &(Arc<String>, Channel)| {
+            if data.1.notification_count > 0 || data.1.highlight_count > 0 {
+                let font = env.get(theme::UI_FONT).with_weight(FontWeight::BOLD);
+                env.set(theme::UI_FONT, font);
+            }
+        });
+    let badge = widget::Either::new(
+        |data: &(Arc<String>, Channel), _| data.1.highlight_count > 0,
+        create_badge(Color::rgb8(0xd0, 0x3e, 0x3e)),
+        widget::Either::new(
+            |data: &(Arc<String>, Channel), _| data.1.notification_count > 0,
+            create_badge(Color::grey8(0x70)),
+            widget::SizedBox::empty(),
+        ),
+    );
+
+    widget::Flex::row()
+        .with_child(avatar)
+        .with_flex_child(name, 1.0)
+        .with_child(badge)
+        .padding((5.0, 4.0))
+        .background(theme::BACKGROUND_LIGHT)
+        .env_scope(|env, (current_channel, channel): &(Arc<String>, Channel)| {
+            if *current_channel == channel.id {
+                env.set(theme::BACKGROUND_LIGHT, env.get(theme::BUTTON_LIGHT));
+            }
+        })
         .on_click(|_, (current_channel, channel), _| *current_channel = channel.id.clone())
+        .controller(ChannelEntryController)
 }
 
 #[derive(Data, Clone, Copy, PartialEq)]
@@ -935,16 +3908,66 @@ where
     }
 }
 
+trait HasAvatar {
+    fn avatar(&self) -> &AvatarState;
+    fn set_avatar(&mut self, avatar: AvatarState);
+    fn media_tx(&self) -> &mpsc::Sender<MediaFetch>;
+}
+
+impl HasAvatar for Message {
+    fn avatar(&self) -> &AvatarState {
+        &self.avatar
+    }
+
+    fn set_avatar(&mut self, avatar: AvatarState) {
+        self.avatar = avatar;
+    }
+
+    fn media_tx(&self) -> &mpsc::Sender<MediaFetch> {
+        &self.txs.media_tx
+    }
+}
+
+impl HasAvatar for DirectInfo {
+    fn avatar(&self) -> &AvatarState {
+        &self.avatar
+    }
+
+    fn set_avatar(&mut self, avatar: AvatarState) {
+        self.avatar = avatar;
+    }
+
+    fn media_tx(&self) -> &mpsc::Sender<MediaFetch> {
+        &self.txs.media_tx
+    }
+}
+
+impl HasAvatar for ReadReceipt {
+    fn avatar(&self) -> &AvatarState {
+        &self.avatar
+    }
+
+    fn set_avatar(&mut self, avatar: AvatarState) {
+        self.avatar = avatar;
+    }
+
+    fn media_tx(&self) -> &mpsc::Sender<MediaFetch> {
+        &self.txs.media_tx
+    }
+}
+
 struct AvatarController;
 
-impl<W> widget::Controller<Message, W> for AvatarController
-    where W: widget::Widget<Message>
+impl<T, W> widget::Controller<T, W> for AvatarController
+    where T: HasAvatar + Data,
+          W: widget::Widget<T>
 {
-    fn event(&mut self, child: &mut W, ctx: &mut EventCtx, event: &Event, data: &mut Message, env: &Env) {
+    fn event(&mut self, child: &mut W, ctx: &mut EventCtx, event: &Event, data: &mut T, env: &Env) {
         match event {
             Event::Command(cmd) if cmd.is(SYNC) => {
-                if let AvatarState::Name(name) = &data.avatar {
-                    match data.txs.media_tx.try_send(MediaFetch::AvatarFetch(
+                if let AvatarState::Name(name) = data.avatar() {
+                    let name = name.clone();
+                    match data.media_tx().try_send(MediaFetch::AvatarFetch(
                         name.clone(),
                         ctx.widget_id(),
                     )) {
@@ -952,7 +3975,7 @@ impl<W> widget::Controller<Message, W> for AvatarController
                         Err(TrySendError::Full(_)) => panic!("oh no"),
                         Err(TrySendError::Closed(_)) => panic!("oh no"),
                     }
-                    data.avatar = AvatarState::Processing(name.clone());
+                    data.set_avatar(AvatarState::Processing(name));
                     ctx.set_handled();
                 } else {
                     child.event(ctx, event, data, env);
@@ -962,9 +3985,9 @@ impl<W> widget::Controller<Message, W> for AvatarController
             Event::Command(cmd) if cmd.is(FETCH_THUMBNAIL) => {
                 let image = cmd.get_unchecked(FETCH_THUMBNAIL);
 
-                data.avatar = AvatarState::Image(
+                data.set_avatar(AvatarState::Image(
                     Arc::new(image.clone()),
-                );
+                ));
                 ctx.set_handled();
             }
 
@@ -973,6 +3996,34 @@ impl<W> widget::Controller<Message, W> for AvatarController
     }
 }
 
+fn create_avatar<T: HasAvatar + Data>(size: f64) -> impl Widget<T> {
+    widget::ViewSwitcher::new(|data: &T, _| matches!(data.avatar(), AvatarState::Image(_)), |_, data: &T, _| {
+        match data.avatar() {
+            AvatarState::Name(_)
+            | AvatarState::Processing(_) => widget::Image::new(ImageBuf::empty())
+                .boxed(),
+            AvatarState::Image(buffer) => widget::Image::new((**buffer).clone())
+                .boxed(),
+        }
+    })
+        .controller(AvatarController)
+        .fix_size(size, size)
+}
+
+fn create_reaction_chip() -> impl Widget<Reaction> {
+    widget::Button::dynamic(|data: &Reaction, _| format!("{} {}", data.key, data.annotations.len()))
+        .on_click(|ctx, data: &mut Reaction, _| {
+            ctx.submit_command(TOGGLE_REACTION.with((data.channel.clone(), data.target.clone(), data.key.clone())));
+        })
+        .env_scope(|env, data: &Reaction| {
+            if data.own {
+                env.set(theme::BUTTON_LIGHT, Color::rgb8(0x4a, 0x6e, 0xb0));
+                env.set(theme::BUTTON_DARK, Color::rgb8(0x3a, 0x5a, 0x96));
+            }
+        })
+        .padding((0.0, 0.0, 2.0, 0.0))
+}
+
 fn create_message() -> impl Widget<Message> {
     let contents = widget::ViewSwitcher::new(
         |data: &Message, _| {
@@ -1008,14 +4059,88 @@ fn create_message() -> impl Widget<Message> {
                     _ => panic!("nyaaa :("),
                 };
 
-                widget::Image::new(buffer).boxed()
+                widget::Image::new(buffer)
+                    .on_click(|ctx, data: &mut Message, _| {
+                        if let Some(url) = &data.media_url {
+                            ctx.submit_command(OPEN_IMAGE.with((url.clone(), data.contents.clone())));
+                        }
+                    })
+                    .boxed()
             }
         },
     );
     let sender = widget::Label::dynamic(|v: &Message, _| (*v.sender).clone())
-        .with_text_alignment(TextAlignment::Start);
+        .with_text_alignment(TextAlignment::Start)
+        .controller(SenderController);
+    let send_state = widget::ViewSwitcher::new(
+        |data: &Message, _| data.send_state,
+        |state, _, _| match state {
+            SendState::Sent => widget::SizedBox::empty().boxed(),
+
+            SendState::Sending => widget::Label::new("sending…")
+                .with_text_color(Color::GRAY)
+                .with_text_size(10.0)
+                .boxed(),
+
+            SendState::Failed => widget::Flex::row()
+                .with_child(widget::Label::new("failed to send")
+                    .with_text_color(Color::rgb8(0xe0, 0x40, 0x40))
+                    .with_text_size(10.0))
+                .with_spacer(4.0)
+                .with_child(widget::Button::new("Retry").on_click(|_, data: &mut Message, _| {
+                    data.send_state = SendState::Sending;
+                    data.send();
+                }))
+                .with_child(widget::Button::new("Discard").on_click(|ctx, data: &mut Message, _| {
+                    if let Some(txn_id) = &data.transaction_id {
+                        ctx.submit_command(DISCARD_MESSAGE.with((data.channel.clone(), txn_id.clone())));
+                    }
+                }))
+                .boxed(),
+        },
+    );
+    let delete_button = widget::Button::new("Delete")
+        .on_click(|_, data: &mut Message, _| {
+            if data.event_id.is_empty() || data.redacted {
+                return;
+            }
+
+            match data.txs.action_tx.try_send(UserAction::RedactEvent(
+                data.channel.clone(),
+                data.event_id.clone(),
+                None,
+            )) {
+                Ok(_) => (),
+                Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+                Err(TrySendError::Closed(_)) => panic!("oh no"),
+            }
+        });
+    let reply_button = widget::Button::new("Reply")
+        .on_click(|ctx, data: &mut Message, _| {
+            if data.event_id.is_empty() || data.redacted {
+                return;
+            }
+
+            ctx.submit_command(REPLY.with(Replying {
+                channel: data.channel.clone(),
+                event_id: data.event_id.clone(),
+                sender: data.sender.clone(),
+                contents: data.contents.clone(),
+                html: data.html.clone(),
+            }));
+        });
+    let react_button = widget::Button::new("React")
+        .on_click(|_, data: &mut Message, _| {
+            if !data.event_id.is_empty() && !data.redacted {
+                data.picking_reaction ^= true;
+            }
+        });
     let edit_button = widget::Button::new("...")
         .on_click(|_, data: &mut Message, _| {
+            if data.event_id.is_empty() || data.redacted {
+                return;
+            }
+
             data.editing ^= true;
             if data.editing {
                 data.editing_message = data.contents.clone();
@@ -1024,25 +4149,59 @@ fn create_message() -> impl Widget<Message> {
         .align_right();
     let mut row = widget::Flex::row()
         .with_child(sender)
+        .with_spacer(4.0)
+        .with_child(send_state)
         .with_flex_spacer(1.0)
+        .with_child(reply_button)
+        .with_child(react_button)
+        .with_child(delete_button)
         .with_child(edit_button);
     row.set_cross_axis_alignment(CrossAxisAlignment::Start);
+    let reactions = widget::List::new(create_reaction_chip)
+        .horizontal()
+        .lens(Message::reactions);
+    let picker = widget::Either::new(
+        |data: &Message, _| data.picking_reaction,
+        QUICK_REACTIONS.iter().fold(widget::Flex::row(), |row, &key| {
+            row.with_child(widget::Button::new(key).on_click(move |ctx, data: &mut Message, _| {
+                data.picking_reaction = false;
+                ctx.submit_command(TOGGLE_REACTION.with((data.channel.clone(), data.event_id.clone(), Arc::new(String::from(key)))));
+            }))
+        }),
+        widget::SizedBox::empty(),
+    );
+    let quote = widget::Either::new(
+        |data: &Message, _| data.reply_to.is_some(),
+        widget::Label::dynamic(|data: &Message, _| match &data.reply_preview {
+            Some(preview) if preview.sender.is_empty() => format!("↳ {}", preview.text),
+            Some(preview) => format!("↳ {}: {}", preview.sender, preview.text),
+            None => String::from("↳ loading…"),
+        })
+        .with_text_color(Color::GRAY)
+        .with_text_size(12.0)
+        .with_line_break_mode(LineBreaking::Clip)
+        .padding((6.0, 2.0)),
+        widget::SizedBox::empty(),
+    );
+    let read_by = widget::Flex::row()
+        .with_flex_spacer(1.0)
+        .with_child(
+            widget::List::new(|| create_avatar(16.0).padding((1.0, 0.0)))
+                .horizontal()
+                .lens(Message::read_by),
+        )
+        .expand_width();
     let mut column = widget::Flex::column()
         .with_child(row)
         .with_spacer(2.0)
-        .with_child(contents);
+        .with_child(quote)
+        .with_child(contents)
+        .with_spacer(2.0)
+        .with_child(reactions)
+        .with_child(picker)
+        .with_child(read_by);
     column.set_cross_axis_alignment(CrossAxisAlignment::Start);
-    let avatar = widget::ViewSwitcher::new(|data: &Message, _| matches!(data.avatar, AvatarState::Image(_)), |_, data, _| {
-        match &data.avatar {
-            AvatarState::Name(_)
-            | AvatarState::Processing(_) => widget::Image::new(ImageBuf::empty())
-                .boxed(),
-            AvatarState::Image(buffer) => widget::Image::new((**buffer).clone())
-                .boxed(),
-        }
-    })
-        .controller(AvatarController)
-        .fix_size(32.0, 32.0);
+    let avatar = create_avatar(32.0);
     let mut row = widget::Flex::row()
         .with_child(avatar)
         .with_spacer(2.0)
@@ -1051,6 +4210,100 @@ fn create_message() -> impl Widget<Message> {
     widget::Container::new(row).padding(5.0).expand_width()
 }
 
+// sits outside the viewer's lens, so it can hand work off to the workers and clean up after
+// the window once it's gone
+struct ViewerController(Arc<String>);
+
+impl<W> widget::Controller<Chat, W> for ViewerController
+where
+    W: Widget<Chat>,
+{
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut Chat,
+        env: &Env,
+    ) {
+        match event {
+            Event::Command(cmd) if cmd.is(commands::SAVE_FILE_AS) => {
+                let target = cmd.get_unchecked(commands::SAVE_FILE_AS).path();
+                if let Some(path) = data.viewers.get(&self.0).and_then(|v| v.path.clone()) {
+                    match data.txs.media_tx.try_send(MediaFetch::Save(path, target.to_path_buf())) {
+                        Ok(_) => (),
+                        Err(TrySendError::Full(_)) => panic!("idk what to do here :("),
+                        Err(TrySendError::Closed(_)) => panic!("oh no"),
+                    }
+                }
+                ctx.set_handled();
+            }
+
+            Event::WindowDisconnected => {
+                if let Some(viewer) = data.viewers.get_mut(&self.0) {
+                    viewer.windows = viewer.windows.saturating_sub(1);
+                    if viewer.windows == 0 {
+                        data.viewers.remove(&self.0);
+                    }
+                }
+                child.event(ctx, event, data, env);
+            }
+
+            _ => child.event(ctx, event, data, env),
+        }
+    }
+}
+
+fn build_image_viewer(url: Arc<String>) -> impl Widget<Chat> {
+    let image = ImageViewer::new().lens(Viewer::image.map(
+        |v| match v {
+            Some(v) => (**v).clone(),
+            None => ImageBuf::empty(),
+        },
+        |_, _| (),
+    ));
+    let loading = widget::Flex::column()
+        .with_child(widget::Label::new("downloading…"))
+        .with_spacer(5.0)
+        .with_child(widget::ProgressBar::new().lens(Viewer::progress))
+        .center();
+    let error = widget::Label::dynamic(|data: &Viewer, _| match &data.error {
+        Some(e) => format!("could not load image: {}", e),
+        None => String::new(),
+    })
+    .with_line_break_mode(LineBreaking::WordWrap)
+    .center();
+    let body = widget::Either::new(
+        |data: &Viewer, _| data.image.is_some(),
+        image,
+        widget::Either::new(|data: &Viewer, _| data.error.is_some(), error, loading),
+    );
+
+    let save = widget::Button::new("Save as…")
+        .on_click(|ctx, data: &mut Viewer, _| {
+            let options = FileDialogOptions::new()
+                .title("Save image")
+                .default_name(data.filename.as_str());
+            ctx.submit_command(commands::SHOW_SAVE_PANEL.with(options));
+        })
+        .disabled_if(|data: &Viewer, _| data.path.is_none());
+    let toolbar = widget::Flex::row()
+        .with_child(widget::Button::new("Zoom in").on_click(|ctx, _: &mut Viewer, _| ctx.submit_command(widgets::ZOOM_IN)))
+        .with_child(widget::Button::new("Zoom out").on_click(|ctx, _: &mut Viewer, _| ctx.submit_command(widgets::ZOOM_OUT)))
+        .with_child(widget::Button::new("Fit").on_click(|ctx, _: &mut Viewer, _| ctx.submit_command(widgets::FIT)))
+        .with_child(widget::Button::new("1:1").on_click(|ctx, _: &mut Viewer, _| ctx.submit_command(widgets::ACTUAL_SIZE)))
+        .with_flex_spacer(1.0)
+        .with_child(save);
+
+    widget::Flex::column()
+        .with_child(toolbar)
+        .with_spacer(5.0)
+        .with_flex_child(body, 1.0)
+        .padding(5.0)
+        .lens(ViewerLens(url.clone()))
+        .controller(ViewerController(url))
+}
+
 pub fn build_ui() -> impl Widget<Chat> {
     let messages = widget::List::new(create_message)
         .lens(CurrentChannelLens.map(
@@ -1091,18 +4344,212 @@ pub fn build_ui() -> impl Widget<Chat> {
         .with_placeholder("Say hello!")
         .lens(Chat::editing_message)
         .expand_width()
-        .controller(MessageEntryController)
+        .controller(MessageEntryController::new())
         .scroll()
         .vertical();
+    let attach = widget::Button::new("Attach")
+        .on_click(|ctx, data: &mut Chat, _| {
+            if data.current_channel.is_empty() {
+                return;
+            }
+
+            let options = FileDialogOptions::new()
+                .title("Send a file")
+                .button_text("Send");
+            ctx.submit_command(commands::SHOW_OPEN_PANEL.with(options));
+        });
+    let composer = widget::Flex::row()
+        .cross_axis_alignment(CrossAxisAlignment::End)
+        .with_child(attach)
+        .with_flex_child(textbox, 1.0);
+    let typing = widget::Either::new(
+        |data: &Chat, _| match data.channels_hashed.get(&data.current_channel) {
+            Some(channel) => !channel.typing.is_empty(),
+            None => false,
+        },
+        widget::Label::dynamic(|data: &Chat, _| {
+            let channel = match data.channels_hashed.get(&data.current_channel) {
+                Some(v) => v,
+                None => return String::new(),
+            };
+
+            let typing: Vec<&str> = channel.typing.iter().map(|v| channel.naming.member_name(v)).collect();
+            match typing.len() {
+                0 => String::new(),
+                1 => format!("{} is typing…", typing[0]),
+                2 => format!("{} and {} are typing…", typing[0], typing[1]),
+                3 => format!("{}, {} and {} are typing…", typing[0], typing[1], typing[2]),
+                _ => String::from("several people are typing…"),
+            }
+        })
+        .with_text_color(Color::GRAY)
+        .with_text_size(12.0),
+        widget::SizedBox::empty(),
+    );
+    let replying = widget::Either::new(
+        |data: &Chat, _| data.replying_to.is_some(),
+        widget::Flex::row()
+            .with_child(widget::Label::dynamic(|data: &Chat, _| match &data.replying_to {
+                Some(v) => format!("Replying to {}", v.sender),
+                None => String::new(),
+            }))
+            .with_flex_spacer(1.0)
+            .with_child(widget::Button::new("Cancel").on_click(|_, data: &mut Chat, _| data.replying_to = None))
+            .padding((0.0, 2.0)),
+        widget::SizedBox::empty(),
+    );
+    let notice = widget::Either::new(
+        |data: &Chat, _| data.notice.is_some(),
+        widget::Label::dynamic(|data: &Chat, _| match &data.notice {
+            Some(v) => (**v).clone(),
+            None => String::new(),
+        })
+        .with_text_color(Color::rgb8(0xff, 0x60, 0x60))
+        .with_text_size(12.0),
+        widget::SizedBox::empty(),
+    );
     let right = widget::Flex::column()
         .with_flex_child(messages, 1.0)
-        .with_child(textbox);
+        .with_child(typing)
+        .with_child(replying)
+        .with_child(notice)
+        .with_child(composer);
+    let right = widget::Either::new(
+        |data: &Chat, _| data.new_room.is_some(),
+        widget::Scroll::new(widget::Maybe::or_empty(build_new_room)).vertical().lens(Chat::new_room),
+        right,
+    );
+    let right = widget::Either::new(
+        |data: &Chat, _| data.settings.is_some(),
+        widget::Scroll::new(widget::Maybe::or_empty(build_settings)).vertical().lens(Chat::settings),
+        right,
+    );
 
-    let channels = widget::List::new(create_channel_listing).lens(AllChannelsLens);
+    let channels = widget::Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(build_invites())
+        .with_child(widget::List::new(create_channel_section).lens(ChannelSectionsLens));
     let channels = widget::Scroll::new(channels).vertical();
-    widget::Split::columns(channels, right)
-        .split_point(0.2)
+    let filter = widget::TextBox::new()
+        .with_placeholder("Filter rooms")
+        .lens(Chat::channel_filter)
+        .expand_width();
+    let sort = widget::Button::dynamic(|data: &Chat, _| match data.sort {
+        SortMode::Recent => String::from("Sort: recent"),
+        SortMode::Alphabetical => String::from("Sort: A-Z"),
+        SortMode::Unread => String::from("Sort: unread"),
+    })
+    .on_click(|_, data: &mut Chat, _| {
+        data.sort = match data.sort {
+            SortMode::Recent => SortMode::Alphabetical,
+            SortMode::Alphabetical => SortMode::Unread,
+            SortMode::Unread => SortMode::Recent,
+        };
+    });
+    let logout = widget::Button::new("Log out")
+        .on_click(|_, data: &mut Chat, _| data.log_out());
+    let connection = widget::Flex::row()
+        .with_child(
+            widget::Label::dynamic(|data: &Chat, _| {
+                String::from(match data.connection {
+                    Connection::Connected => "● Connected",
+                    Connection::Reconnecting => "● Reconnecting…",
+                    Connection::Offline => "● Offline",
+                })
+            })
+            .with_text_size(12.0)
+            .with_text_color(CONNECTION_COLOR)
+            .env_scope(|env, data: &Chat| {
+                let color = match data.connection {
+                    Connection::Connected => Color::rgb8(0x60, 0xc0, 0x60),
+                    Connection::Reconnecting => Color::rgb8(0xe0, 0xb0, 0x40),
+                    Connection::Offline => Color::rgb8(0xff, 0x60, 0x60),
+                };
+                env.set(CONNECTION_COLOR, color);
+            }),
+        )
+        .with_flex_spacer(1.0)
+        .with_child(widget::Either::new(
+            |data: &Chat, _| data.connection != Connection::Connected,
+            widget::Button::new("Reconnect now").on_click(|ctx, _, _| ctx.submit_command(RECONNECT)),
+            widget::SizedBox::empty(),
+        ))
+        .padding((0.0, 2.0));
+    let start_dm = widget::Button::new("Start DM")
+        .on_click(|_, data: &mut Chat, _| {
+            data.prompt = Some(Prompt::StartDm);
+            data.prompt_text = Arc::new(String::new());
+        });
+    let prompt = widget::Either::new(
+        |data: &Chat, _| data.prompt.is_some(),
+        widget::Flex::column()
+            .cross_axis_alignment(CrossAxisAlignment::Start)
+            .with_child(
+                widget::Label::dynamic(|data: &Chat, _| match &data.prompt {
+                    Some(Prompt::Tag(_)) => String::from("Tag name"),
+                    Some(Prompt::StartDm) => String::from("User ID, like @alice:example.org"),
+                    Some(Prompt::Join) => String::from("Room or space, like #uwutalk:example.org"),
+                    None => String::new(),
+                })
+                .with_text_size(12.0),
+            )
+            .with_child(
+                widget::Flex::row()
+                    .with_flex_child(
+                        widget::TextBox::new()
+                            .lens(Chat::prompt_text)
+                            .expand_width()
+                            .controller(PromptController),
+                        1.0,
+                    )
+                    .with_child(widget::Button::new("Cancel").on_click(|_, data: &mut Chat, _| data.prompt = None)),
+            ),
+        widget::SizedBox::empty(),
+    );
+    let new_room = widget::Button::new("New room")
+        .on_click(|_, data: &mut Chat, _| {
+            data.settings = None;
+            data.new_room = Some(NewRoom::new());
+        });
+    let channels = widget::Flex::column()
+        .with_child(filter)
+        .with_child(sort)
+        .with_child(widget::Flex::row().with_child(start_dm).with_spacer(4.0).with_child(new_room))
+        .with_child(prompt)
+        .with_spacer(2.0)
+        .with_flex_child(channels, 1.0)
+        .with_child(connection)
+        .with_child(logout);
+    let channels = widget::Flex::row()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(build_rail())
+        .with_flex_child(channels, 1.0);
+    let chat = widget::Split::columns(channels, right)
+        .split_point(0.25);
+    let chat = widget::Either::new(|data: &Chat, _| data.switcher.open, build_switcher(), chat);
+
+    widget::Either::new(|data: &Chat, _| data.logged_in, chat, build_login().lens(Chat::login))
         .controller(ChatController)
         .padding(5.0)
         // .debug_paint_layout()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_keeps_typed_and_pasted_masks() {
+        let (password, cursor) = splice_password("hunter", 6..6, "*");
+        assert_eq!((password.as_str(), cursor), ("hunter*", 7));
+
+        let (password, cursor) = splice_password(&password, 2..2, "*x*");
+        assert_eq!((password.as_str(), cursor), ("hu*x*nter*", 5));
+
+        let (password, cursor) = splice_password(&password, 4..5, "");
+        assert_eq!((password.as_str(), cursor), ("hu*xnter*", 4));
+
+        let (password, cursor) = splice_password(&password, 0..0, "ü");
+        assert_eq!((password.as_str(), cursor), ("ühu*xnter*", 1));
+    }
+}
@@ -0,0 +1,155 @@
+use std::fmt::{self, Display, Formatter};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorCode {
+    Forbidden,
+    UnknownToken,
+    MissingToken,
+    BadJson,
+    NotJson,
+    NotFound,
+    LimitExceeded,
+    Unknown,
+    Unrecognized,
+    UserInUse,
+    RoomInUse,
+    Other(String),
+}
+
+impl From<&str> for ErrorCode {
+    fn from(errcode: &str) -> ErrorCode {
+        match errcode {
+            "M_FORBIDDEN" => ErrorCode::Forbidden,
+            "M_UNKNOWN_TOKEN" => ErrorCode::UnknownToken,
+            "M_MISSING_TOKEN" => ErrorCode::MissingToken,
+            "M_BAD_JSON" => ErrorCode::BadJson,
+            "M_NOT_JSON" => ErrorCode::NotJson,
+            "M_NOT_FOUND" => ErrorCode::NotFound,
+            "M_LIMIT_EXCEEDED" => ErrorCode::LimitExceeded,
+            "M_UNKNOWN" => ErrorCode::Unknown,
+            "M_UNRECOGNIZED" => ErrorCode::Unrecognized,
+            "M_USER_IN_USE" => ErrorCode::UserInUse,
+            "M_ROOM_IN_USE" => ErrorCode::RoomInUse,
+            _ => ErrorCode::Other(String::from(errcode)),
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::Forbidden => write!(f, "M_FORBIDDEN"),
+            ErrorCode::UnknownToken => write!(f, "M_UNKNOWN_TOKEN"),
+            ErrorCode::MissingToken => write!(f, "M_MISSING_TOKEN"),
+            ErrorCode::BadJson => write!(f, "M_BAD_JSON"),
+            ErrorCode::NotJson => write!(f, "M_NOT_JSON"),
+            ErrorCode::NotFound => write!(f, "M_NOT_FOUND"),
+            ErrorCode::LimitExceeded => write!(f, "M_LIMIT_EXCEEDED"),
+            ErrorCode::Unknown => write!(f, "M_UNKNOWN"),
+            ErrorCode::Unrecognized => write!(f, "M_UNRECOGNIZED"),
+            ErrorCode::UserInUse => write!(f, "M_USER_IN_USE"),
+            ErrorCode::RoomInUse => write!(f, "M_ROOM_IN_USE"),
+            ErrorCode::Other(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Transport(reqwest::Error),
+
+    Status {
+        status: u16,
+        errcode: ErrorCode,
+        error: String,
+        retry_after_ms: Option<u64>,
+    },
+
+    Json(serde_json::Error),
+
+    Io(std::io::Error),
+
+    Store(rusqlite::Error),
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    errcode: Option<String>,
+    error: Option<String>,
+    retry_after_ms: Option<u64>,
+}
+
+impl Error {
+    pub(crate) fn from_status(status: u16, body: &str) -> Error {
+        let body = serde_json::from_str::<ErrorBody>(body).unwrap_or(ErrorBody {
+            errcode: None,
+            error: None,
+            retry_after_ms: None,
+        });
+
+        Error::Status {
+            status,
+            errcode: body.errcode.as_deref().unwrap_or("M_UNKNOWN").into(),
+            error: body.error.unwrap_or_default(),
+            retry_after_ms: body.retry_after_ms,
+        }
+    }
+
+    pub fn errcode(&self) -> Option<&ErrorCode> {
+        match self {
+            Error::Status { errcode, .. } => Some(errcode),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "{}", e),
+            Error::Status { status, errcode, error, .. } if error.is_empty() => write!(f, "{} ({})", errcode, status),
+            Error::Status { status, errcode, error, .. } => write!(f, "{}: {} ({})", errcode, error, status),
+            Error::Json(e) => write!(f, "malformed response: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Store(e) => write!(f, "store: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(e) => Some(e),
+            Error::Status { .. } => None,
+            Error::Json(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Store(e) => Some(e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::Transport(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Store(e)
+    }
+}
@@ -1,4 +1,6 @@
 pub mod chat;
 pub mod chat_gui;
+pub mod error;
 pub mod markdown;
+pub mod store;
 pub mod widgets;
@@ -1,13 +1,18 @@
 use tokio::fs;
 use std::collections::HashMap;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use druid::{AppLauncher, ImageBuf, Target, WindowDesc};
+use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc;
 
 use directories::ProjectDirs;
-use uwutalk::chat::{MatrixClient, RoomDirection};
+use uwutalk::chat::{self, Attachment, MatrixClient, RoomDirection, Session};
 use uwutalk::chat_gui::{self, Chat};
+use uwutalk::error::Error;
+use uwutalk::store::Store;
 
 macro_rules! fetch_thumbnail {
     ($url: ident, $widget: ident, $width: ident, $height: ident, $thumbnails_map: ident, $event_sink: ident, $client: ident, $thumbnails: ident) => {
@@ -120,6 +125,125 @@ macro_rules! fetch_thumbnail {
     }
 }
 
+async fn send_attachment(client: &MatrixClient, room_id: &str, txn_id: &str, path: &Path) -> Result<chat::Event, Error> {
+    let data = fs::read(path).await?;
+    let filename = path
+        .file_name()
+        .map(|v| v.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("file"));
+    let attachment = Attachment {
+        mimetype: String::from(chat::guess_mimetype(&filename)),
+        size: data.len() as u64,
+        filename,
+    };
+
+    // decode images locally so the other clients know how big they are
+    let dimensions = if attachment.mimetype.starts_with("image/") {
+        let data = data.clone();
+        match tokio::task::spawn_blocking(move || ImageBuf::from_data(&data)).await {
+            Ok(Ok(v)) => Some((v.width() as u64, v.height() as u64)),
+            Ok(Err(e)) => {
+                eprintln!("error decoding image, sending as a file: {:?}", e);
+                None
+            }
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    } else {
+        None
+    };
+
+    let url = client.upload_media(&attachment.mimetype, &attachment.filename, data).await?;
+    match dimensions {
+        Some((width, height)) => client.send_image(room_id, txn_id, &url, &attachment, width, height).await,
+        None => client.send_file(room_id, txn_id, &url, &attachment).await,
+    }
+}
+
+async fn write_session(path: &Path, session: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    // the access token is as good as a password, so other users on the machine can't read it
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    file.write_all(session.as_bytes()).await
+}
+
+// bumping the generation under the lock means a sync that was already in flight sees it before
+// it gets to write the old account back into the store
+async fn clear_store(store: Arc<Mutex<Store>>, generation: Arc<AtomicU64>) {
+    let cleared = tokio::task::spawn_blocking(move || {
+        let mut store = store.lock().unwrap();
+        if let Err(e) = store.clear() {
+            eprintln!("error clearing store: {}", e);
+        }
+        generation.fetch_add(1, Ordering::SeqCst);
+    });
+
+    if let Err(e) = cleared.await {
+        std::panic::resume_unwind(e.into_panic());
+    }
+}
+
+async fn restore_sync(store: Arc<Mutex<Store>>) -> Option<chat::SyncState> {
+    let restored = tokio::task::spawn_blocking(move || {
+        let mut store = store.lock().unwrap();
+        store.load().or_else(|e| {
+            // whatever's in there is no use to us, so start over with a full sync
+            eprintln!("error loading store: {}", e);
+            store.clear().map(|_| None)
+        })
+    });
+
+    match restored.await {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            eprintln!("error clearing store: {}", e);
+            None
+        }
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+async fn sync_and_store(
+    client: &MatrixClient,
+    store: Arc<Mutex<Store>>,
+    generation: Arc<AtomicU64>,
+    next_batch: Option<Arc<String>>,
+    filter: &chat::Filter,
+) -> Result<chat::SyncState, Error> {
+    let started = generation.load(Ordering::SeqCst);
+    let since = next_batch.clone();
+
+    // the initial sync always has something to say, so there's nothing to wait for
+    let timeout = next_batch.as_ref().map(|_| chat_gui::SYNC_TIMEOUT);
+    let body = client.get_sync(next_batch, Some(filter), timeout).await?;
+
+    let state = tokio::task::spawn_blocking(move || {
+        chat::parse_sync(&body).inspect(|_| {
+            let mut store = store.lock().unwrap();
+            if generation.load(Ordering::SeqCst) != started {
+                return;
+            }
+            if let Err(e) = store.save_sync(since.as_deref().map(String::as_str), &body) {
+                eprintln!("error saving sync to store: {}", e);
+            }
+        })
+    });
+
+    match state.await {
+        Ok(v) => v,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let project = ProjectDirs::from("xyz", "lauwa", "uwutalk")
@@ -142,56 +266,210 @@ async fn main() {
         }
     }
 
-    let file = fs::read_to_string(".env").await.unwrap();
-    let mut contents = file.split('\n');
-    let access_token = contents.next().unwrap();
-    let homeserver = contents.next().unwrap();
+    let media = cache.join("media");
+    match fs::create_dir_all(&media).await {
+        Ok(_) => (),
+        Err(e) => {
+            eprintln!("error creating media directory: {:?}", e);
+            std::process::exit(-1);
+        }
+    }
+
+    let data = project.data_dir();
+    match fs::create_dir_all(&data).await {
+        Ok(_) => (),
+        Err(e) => {
+            eprintln!("error creating data directory: {:?}", e);
+            std::process::exit(-1);
+        }
+    }
+
+    let session_path = data.join("session.json");
+    let store = match Store::open(data.join("store.sqlite3")) {
+        Ok(v) => Arc::new(Mutex::new(v)),
+        Err(e) => {
+            eprintln!("error opening store: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let session = match fs::read_to_string(&session_path).await {
+        Ok(v) => match serde_json::from_str::<Session>(&v) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                eprintln!("error reading saved session: {:?}", e);
+                None
+            }
+        },
 
-    let client = MatrixClient::new(homeserver, access_token);
+        Err(_) => None,
+    };
+    let user_id = session.as_ref().map(|v| v.user_id.clone());
 
-    //let result = client.get_state(None).await.unwrap();
-    //println!("{:#?}", result.rooms.join.iter().next().unwrap().1.timeline);
+    let client = match session {
+        Some(session) => MatrixClient::with_session(session),
+        None => MatrixClient::new(),
+    };
 
     let launcher =
         AppLauncher::with_window(WindowDesc::new(chat_gui::build_ui()).window_size((800., 600.)));
 
+    // bumped every time the store is cleared, see clear_store
+    let generation = Arc::new(AtomicU64::new(0));
+
+    // long polls hold on for up to SYNC_TIMEOUT, so they get their own task instead of holding
+    // backfill, replies and everything else on the sync queue up behind them
+    let poll_client = client.clone();
+    let poll_store = store.clone();
+    let poll_generation = generation.clone();
+    let (poll_tx, mut rx) = mpsc::unbounded_channel();
+    let event_sink = launcher.get_external_handle();
+
+    // not waited on when quitting, it could be in the middle of a long poll
+    tokio::spawn(async move {
+        let client = poll_client;
+        let store = poll_store;
+        let generation = poll_generation;
+        use uwutalk::chat_gui::Syncing::*;
+
+        while let Some(msg) = rx.recv().await {
+            if let ClientSync(session, next_batch, filter) = msg {
+                let next_batch = if next_batch.is_empty() {
+                    None
+                } else {
+                    Some(next_batch)
+                };
+
+                // pick up where the last run left off rather than starting from scratch
+                if next_batch.is_none() {
+                    if let Some(v) = restore_sync(store.clone()).await {
+                        if event_sink.submit_command(chat_gui::SYNC, (session, v), Target::Global).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                }
+
+                match sync_and_store(&client, store.clone(), generation.clone(), next_batch, &filter).await {
+                    Ok(v) => {
+                        if event_sink
+                            .submit_command(chat_gui::SYNC, (session, v), Target::Global)
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+
+                    Err(e) => {
+                        if event_sink
+                            .submit_command(chat_gui::SYNC_FAIL, (session, e), Target::Global)
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    });
+
     let (sync_tx, mut rx) = mpsc::channel(32);
     let event_sink = launcher.get_external_handle();
 
+    let sync_client = client.clone();
     let sync = tokio::spawn(async move {
+        let client = sync_client;
         use uwutalk::chat_gui::Syncing::*;
 
-        while let Some(msg) = rx.recv().await {
+        'sync: while let Some(msg) = rx.recv().await {
             match msg {
                 Quit => break,
 
-                ClientSync(next_batch, filter) => {
-                    let next_batch = if next_batch.is_empty() {
-                        None
-                    } else {
-                        Some(next_batch)
-                    };
-                    let filter = if filter.is_empty() {
-                        None
-                    } else {
-                        Some(filter)
+                msg @ ClientSync(..) => {
+                    if poll_tx.send(msg).is_err() {
+                        break;
+                    }
+                }
+
+                FetchHierarchy(spaces) => {
+                    for space in spaces {
+                        let mut rooms = vec![];
+                        let mut from: Option<Arc<String>> = None;
+                        let result = loop {
+                            match client.get_space_hierarchy(&space, from.as_deref().map(String::as_str), Some(50)).await {
+                                Ok(page) => {
+                                    rooms.extend(page.rooms);
+                                    match page.next_batch {
+                                        Some(next) => from = Some(next),
+                                        None => break Ok(()),
+                                    }
+                                }
+
+                                Err(e) => break Err(e),
+                            }
+                        };
+
+                        match result {
+                            Ok(_) => {
+                                if event_sink.submit_command(chat_gui::SPACE_HIERARCHY, (space, rooms), Target::Global).is_err() {
+                                    break 'sync;
+                                }
+                            }
+
+                            Err(e) => eprintln!("error fetching hierarchy for {}: {}", space, e),
+                        }
+                    }
+                }
+
+                FetchRoomState(room_id) => {
+                    let mut state = vec![];
+                    let mut error = None;
+                    for event_type in chat_gui::SETTINGS_STATE.iter() {
+                        match client.get_state_event(&room_id, event_type, "").await {
+                            Ok(Some(content)) => state.push((String::from(*event_type), content)),
+                            Ok(None) => (),
+                            Err(e) => {
+                                error = Some(e);
+                                break;
+                            }
+                        }
+                    }
+
+                    let sent = match error {
+                        None => event_sink.submit_command(chat_gui::ROOM_STATE, (room_id, state), Target::Global),
+                        Some(e) => event_sink.submit_command(chat_gui::ROOM_STATE_FAIL, (room_id, Arc::new(e.to_string())), Target::Global),
                     };
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+
+                FetchDisplayNames(users) => {
+                    for user in users {
+                        match client.fetch_display_name(&user).await {
+                            Ok(Some(name)) => {
+                                if event_sink.submit_command(chat_gui::DISPLAY_NAME, (user, Arc::new(name)), Target::Global).is_err() {
+                                    break 'sync;
+                                }
+                            }
+
+                            Ok(None) => (),
+
+                            Err(e) => eprintln!("error fetching display name for {}: {}", user, e),
+                        }
+                    }
+                }
 
-                    match client.get_state(next_batch, filter).await {
+                FetchEvent(room_id, event_id) => {
+                    match client.get_event(&room_id, &event_id).await {
                         Ok(v) => {
-                            if event_sink
-                                .submit_command(chat_gui::SYNC, v, Target::Global)
-                                .is_err()
-                            {
+                            if event_sink.submit_command(chat_gui::FETCH_EVENT, (room_id, v), Target::Global).is_err() {
                                 break;
                             }
                         }
 
                         Err(e) => {
-                            if event_sink
-                                .submit_command(chat_gui::SYNC_FAIL, e, Target::Global)
-                                .is_err()
-                            {
+                            if event_sink.submit_command(chat_gui::FETCH_EVENT_FAIL, (room_id, event_id, e), Target::Global).is_err() {
                                 break;
                             }
                         }
@@ -199,13 +477,7 @@ async fn main() {
                 }
 
                 FetchFromRoom(room_id, prev_batch, filter) => {
-                    let filter = if filter.is_empty() {
-                        None
-                    } else {
-                        Some(filter)
-                    };
-
-                    match client.get_room_messages(&room_id, &prev_batch, RoomDirection::Backwards, None, Some(50), filter).await {
+                    match client.get_room_messages(&room_id, &prev_batch, RoomDirection::Backwards, None, Some(50), Some(&filter)).await {
                         Ok(v) => {
                             if event_sink.submit_command(chat_gui::FETCH_FROM_ROOM, (room_id, v), Target::Global).is_err() {
                                 break;
@@ -213,7 +485,7 @@ async fn main() {
                         }
 
                         Err(e) => {
-                            if event_sink.submit_command(chat_gui::FETCH_FROM_ROOM_FAIL, e, Target::Global).is_err() {
+                            if event_sink.submit_command(chat_gui::FETCH_FROM_ROOM_FAIL, (room_id, e), Target::Global).is_err() {
                                 break;
                             }
                         }
@@ -223,47 +495,292 @@ async fn main() {
         }
     });
 
-    let client = MatrixClient::new(homeserver, access_token);
-    let (action_tx, mut rx) = mpsc::channel(32);
-    //let event_sink = launcher.get_external_handle();
+    // sends keep retrying while rate limited, so they queue up on their own instead of holding
+    // typing notifications, receipts and everything else up behind them
+    let send_client = client.clone();
+    let (send_tx, mut rx) = mpsc::unbounded_channel();
+    let event_sink = launcher.get_external_handle();
 
-    let action = tokio::spawn(async move {
+    let send = tokio::spawn(async move {
+        let client = send_client;
         use uwutalk::chat_gui::UserAction::*;
 
         while let Some(msg) = rx.recv().await {
             match msg {
-                Quit => break,
-
-                SendMessage(room_id, msg, formatted) => {
+                SendMessage(room_id, txn_id, msg, formatted, reply_to) => {
                     let formatted = if formatted == msg {
                         None
                     } else {
                         Some(formatted)
                     };
 
-                    // TODO: error on send
-                    let _ = client
-                        .send_message(&room_id, &msg, formatted)
+                    let result = client
+                        .send_message(&room_id, &txn_id, &msg, formatted, reply_to.as_ref())
                         .await;
+                    let sent = match result {
+                        Ok(v) => event_sink.submit_command(chat_gui::MESSAGE_SENT, (room_id, txn_id, v.event_id), Target::Global),
+                        Err(e) => event_sink.submit_command(chat_gui::MESSAGE_SEND_FAIL, (room_id, txn_id, e), Target::Global),
+                    };
+                    if sent.is_err() {
+                        break;
+                    }
                 }
 
-                EditMessage(room_id, event_id, msg, formatted) => {
+                EditMessage(room_id, txn_id, event_id, msg, formatted) => {
                     let formatted = if formatted == msg {
                         None
                     } else {
                         Some(formatted)
                     };
 
-                    // TODO: error on send
-                    let _ = client
-                        .edit_message(&room_id, &event_id, &msg, formatted)
+                    let result = client
+                        .edit_message(&room_id, &txn_id, &event_id, &msg, formatted)
                         .await;
+                    let sent = match result {
+                        Ok(v) => event_sink.submit_command(chat_gui::MESSAGE_SENT, (room_id, txn_id, v.event_id), Target::Global),
+                        Err(e) => event_sink.submit_command(chat_gui::MESSAGE_SEND_FAIL, (room_id, txn_id, e), Target::Global),
+                    };
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+
+                SendFile(room_id, txn_id, path) => {
+                    let sent = match send_attachment(&client, &room_id, &txn_id, &path).await {
+                        Ok(v) => event_sink.submit_command(chat_gui::MESSAGE_SENT, (room_id, txn_id, v.event_id), Target::Global),
+                        Err(e) => event_sink.submit_command(chat_gui::MESSAGE_SEND_FAIL, (room_id, txn_id, e), Target::Global),
+                    };
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+
+                SendReaction(room_id, event_id, key) => {
+                    let txn_id = chat::new_transaction_id();
+                    if let Err(e) = client
+                        .send_reaction(&room_id, &txn_id, &event_id, &key)
+                        .await
+                    {
+                        eprintln!("error reacting to {} in {}: {}", event_id, room_id, e);
+                        if event_sink.submit_command(chat_gui::REACTION_FAIL, (room_id, event_id, key), Target::Global).is_err() {
+                            break;
+                        }
+                    }
+                }
+
+                RemoveReaction(room_id, target, key, event_id) => {
+                    let txn_id = chat::new_transaction_id();
+                    if let Err(e) = client.redact_event(&room_id, &event_id, &txn_id, None).await {
+                        eprintln!("error removing reaction {} in {}: {}", event_id, room_id, e);
+                        if event_sink.submit_command(chat_gui::REACTION_FAIL, (room_id, target, key), Target::Global).is_err() {
+                            break;
+                        }
+                    }
+                }
+
+                RedactEvent(room_id, event_id, reason) => {
+                    let txn_id = chat::new_transaction_id();
+                    if let Err(e) = client
+                        .redact_event(&room_id, &event_id, &txn_id, reason.as_deref().map(String::as_str))
+                        .await
+                    {
+                        eprintln!("error redacting {} in {}: {}", event_id, room_id, e);
+                    }
+                }
+
+                _ => (),
+            }
+        }
+    });
+
+    let action_client = client.clone();
+    let (action_tx, mut rx) = mpsc::channel(32);
+    let event_sink = launcher.get_external_handle();
+
+    let action = tokio::spawn(async move {
+        let client = action_client;
+        use uwutalk::chat_gui::UserAction::*;
+
+        while let Some(msg) = rx.recv().await {
+            match msg {
+                Quit => break,
+
+                Login(homeserver, user, password) => {
+                    match client.login(&homeserver, &user, &password).await {
+                        Ok(session) => {
+                            // a different account's rooms have no business showing up
+                            clear_store(store.clone(), generation.clone()).await;
+
+                            match serde_json::to_string(&session) {
+                                Ok(v) => {
+                                    if let Err(e) = write_session(&session_path, &v).await {
+                                        eprintln!("error saving session: {:?}", e);
+                                    }
+                                }
+
+                                Err(e) => eprintln!("error serialising session: {:?}", e),
+                            }
+
+                            if event_sink.submit_command(chat_gui::LOGGED_IN, session.user_id, Target::Global).is_err() {
+                                break;
+                            }
+                        }
+
+                        Err(e) => {
+                            if event_sink.submit_command(chat_gui::LOGIN_FAIL, e, Target::Global).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                Logout => {
+                    if let Err(e) = client.logout().await {
+                        eprintln!("error logging out: {:?}", e);
+                    }
+
+                    match fs::remove_file(&session_path).await {
+                        Ok(_) => (),
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                        Err(e) => eprintln!("error removing saved session: {:?}", e),
+                    }
+
+                    clear_store(store.clone(), generation.clone()).await;
+                }
+
+                SetTyping(room_id, typing) => {
+                    if let Err(e) = client.set_typing(&room_id, typing, chat_gui::TYPING_TIMEOUT.as_millis() as u64).await {
+                        eprintln!("error setting typing in {}: {}", room_id, e);
+                    }
+                }
+
+                MarkRead(room_id, event_id) => {
+                    if let Err(e) = client
+                        .set_read_markers(&room_id, &event_id, Some(&event_id), None)
+                        .await
+                    {
+                        eprintln!("error marking {} as read in {}: {}", event_id, room_id, e);
+                        if event_sink.submit_command(chat_gui::MARK_READ_FAIL, room_id, Target::Global).is_err() {
+                            break;
+                        }
+                    }
+                }
+
+                SetTag(room_id, tag) => {
+                    if let Err(e) = client.set_room_tag(&room_id, &tag, None).await {
+                        eprintln!("error tagging {} as {}: {}", room_id, tag, e);
+                    }
+                }
+
+                RemoveTag(room_id, tag) => {
+                    if let Err(e) = client.delete_room_tag(&room_id, &tag).await {
+                        eprintln!("error removing tag {} from {}: {}", tag, room_id, e);
+                    }
+                }
+
+                msg @ (SendMessage(..) | EditMessage(..) | SendFile(..) | SendReaction(..) | RemoveReaction(..) | RedactEvent(..)) => {
+                    if send_tx.send(msg).is_err() {
+                        break;
+                    }
+                }
+
+                CreateDirect(user) => {
+                    match client.create_direct_room(&user).await {
+                        Ok(room_id) => {
+                            if event_sink.submit_command(chat_gui::DIRECT_CREATED, (room_id, user), Target::Global).is_err() {
+                                break;
+                            }
+                        }
+
+                        Err(e) => eprintln!("error starting a dm with {}: {}", user, e),
+                    }
+                }
+
+                JoinRoom(room, via) => {
+                    if let Err(e) = client.join_room(&room, &via).await {
+                        if event_sink.submit_command(chat_gui::MEMBERSHIP_FAIL, (room, "join", Arc::new(e.to_string())), Target::Global).is_err() {
+                            break;
+                        }
+                    }
+                }
+
+                LeaveRoom(room_id) => {
+                    match client.leave_room(&room_id).await {
+                        Ok(_) => {
+                            if event_sink.submit_command(chat_gui::ROOM_LEFT, room_id, Target::Global).is_err() {
+                                break;
+                            }
+                        }
+
+                        Err(e) => {
+                            if event_sink.submit_command(chat_gui::MEMBERSHIP_FAIL, (room_id, "leave", Arc::new(e.to_string())), Target::Global).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                CreateRoom(options) => {
+                    match client.create_room(&options).await {
+                        Ok(room_id) => {
+                            let name = Arc::new(options.name.unwrap_or_else(|| (*room_id).clone()));
+                            let is_space = options.room_type.is_some();
+                            if event_sink.submit_command(chat_gui::ROOM_CREATED, (room_id, name, is_space), Target::Global).is_err() {
+                                break;
+                            }
+                        }
+
+                        Err(e) => {
+                            if event_sink.submit_command(chat_gui::ROOM_CREATE_FAIL, Arc::new(e.to_string()), Target::Global).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                Moderate(room_id, action, user, reason) => {
+                    let reason = reason.as_deref().map(String::as_str);
+                    let result = match action {
+                        chat_gui::Moderation::Invite => client.invite_user(&room_id, &user, reason).await,
+                        chat_gui::Moderation::Kick => client.kick_user(&room_id, &user, reason).await,
+                        chat_gui::Moderation::Ban => client.ban_user(&room_id, &user, reason).await,
+                        chat_gui::Moderation::Unban => client.unban_user(&room_id, &user, reason).await,
+                    };
+
+                    if let Err(e) = result {
+                        if event_sink.submit_command(chat_gui::MODERATE_FAIL, (room_id, action, user, Arc::new(e.to_string())), Target::Global).is_err() {
+                            break;
+                        }
+                    }
+                }
+
+                SetState(room_id, event_type, content) => {
+                    if let Err(e) = client.send_state_event(&room_id, event_type, "", content).await {
+                        if event_sink.submit_command(chat_gui::STATE_SEND_FAIL, (room_id, event_type, Arc::new(e.to_string())), Target::Global).is_err() {
+                            break;
+                        }
+                    }
+                }
+
+                RejectInvite(room_id) => {
+                    match client.reject_invite(&room_id).await {
+                        Ok(_) => {
+                            if event_sink.submit_command(chat_gui::ROOM_LEFT, room_id, Target::Global).is_err() {
+                                break;
+                            }
+                        }
+
+                        Err(e) => {
+                            if event_sink.submit_command(chat_gui::MEMBERSHIP_FAIL, (room_id, "decline", Arc::new(e.to_string())), Target::Global).is_err() {
+                                break;
+                            }
+                        }
+                    }
                 }
             }
         }
     });
 
-    let client = MatrixClient::new(homeserver, access_token);
     let (media_tx, mut rx) = mpsc::channel(32);
     let event_sink = launcher.get_external_handle();
 
@@ -284,13 +801,79 @@ async fn main() {
                     let dim = 64;
                     fetch_thumbnail!(url, widget, dim, dim, thumbnails_map, event_sink, client, thumbnails);
                 }
+
+                Save(source, target) => {
+                    if let Err(e) = fs::copy(source.as_path(), &target).await {
+                        eprintln!("error saving {}: {:?}", target.display(), e);
+                    }
+                }
+
+                Download(url) => {
+                    let mut split = url.strip_prefix("mxc://").unwrap_or("").split('/');
+                    let server = split.next().unwrap_or("");
+                    let media_id = split.next().unwrap_or("");
+                    let path = media.join(format!("{}_{}", server, media_id));
+
+                    if !path.exists() {
+                        let mut percent = 0;
+                        let result = client
+                            .download_mxc(server, media_id, &path, |downloaded, total| {
+                                let total = match total {
+                                    Some(v) if v > 0 => v,
+                                    _ => return,
+                                };
+
+                                // only wake the gui up when the bar actually moves
+                                let new = downloaded * 100 / total;
+                                if new != percent {
+                                    percent = new;
+                                    let _ = event_sink.submit_command(chat_gui::DOWNLOAD_PROGRESS, (url.clone(), new as f64 / 100.0), Target::Global);
+                                }
+                            })
+                            .await;
+
+                        if let Err(e) = result {
+                            if event_sink.submit_command(chat_gui::DOWNLOAD_FAIL, (url, Arc::new(e.to_string())), Target::Global).is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    }
+
+                    let content = match fs::read(&path).await {
+                        Ok(v) => v,
+                        Err(e) => {
+                            if event_sink.submit_command(chat_gui::DOWNLOAD_FAIL, (url, Arc::new(e.to_string())), Target::Global).is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+
+                    match tokio::task::spawn_blocking(move || ImageBuf::from_data(&content)).await {
+                        Ok(Ok(v)) => {
+                            if event_sink.submit_command(chat_gui::DOWNLOAD_DONE, (url, v, Arc::new(path)), Target::Global).is_err() {
+                                break;
+                            }
+                        }
+
+                        Ok(Err(e)) => {
+                            if event_sink.submit_command(chat_gui::DOWNLOAD_FAIL, (url, Arc::new(e.to_string())), Target::Global).is_err() {
+                                break;
+                            }
+                        }
+
+                        Err(e) => eprintln!("error spawning blocking thread: {:?}", e),
+                    }
+                }
             }
         }
     });
 
-    launcher.launch(Chat::new(sync_tx, action_tx, media_tx)).unwrap();
+    launcher.launch(Chat::new(sync_tx, action_tx, media_tx, user_id)).unwrap();
     sync.await.unwrap();
     action.await.unwrap();
+    send.await.unwrap();
     media.await.unwrap();
 }
 
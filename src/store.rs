@@ -0,0 +1,402 @@
+use std::path::Path;
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction, NO_PARAMS};
+use serde_json::{json, Map, Value};
+
+use super::chat::{self, SyncState};
+use super::error::Error;
+
+// messages kept per room; anything older is fetched again from the stored prev_batch
+const TIMELINE_LIMIT: i64 = 50;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS sync (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        next_batch TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS rooms (
+        room_id TEXT PRIMARY KEY,
+        prev_batch TEXT NOT NULL,
+        summary TEXT NOT NULL,
+        unread_notifications TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS room_state (
+        room_id TEXT NOT NULL,
+        event_type TEXT NOT NULL,
+        state_key TEXT NOT NULL,
+        event TEXT NOT NULL,
+        PRIMARY KEY (room_id, event_type, state_key)
+    );
+
+    CREATE TABLE IF NOT EXISTS timeline (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        room_id TEXT NOT NULL,
+        prev_batch TEXT NOT NULL,
+        event TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS timeline_room ON timeline (room_id, seq);
+
+    -- global account data has an empty room id
+    CREATE TABLE IF NOT EXISTS account_data (
+        room_id TEXT NOT NULL,
+        event_type TEXT NOT NULL,
+        event TEXT NOT NULL,
+        PRIMARY KEY (room_id, event_type)
+    );
+
+    CREATE TABLE IF NOT EXISTS invites (
+        room_id TEXT PRIMARY KEY,
+        room TEXT NOT NULL
+    );
+";
+
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Store, Error> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Store { conn })
+    }
+
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.conn.execute_batch(
+            "
+            DELETE FROM sync;
+            DELETE FROM rooms;
+            DELETE FROM room_state;
+            DELETE FROM timeline;
+            DELETE FROM account_data;
+            DELETE FROM invites;
+            ",
+        )?;
+        Ok(())
+    }
+
+    // takes the response body as the server sent it, so nothing we don't parse gets lost
+    pub fn save_sync(&mut self, since: Option<&str>, body: &str) -> Result<(), Error> {
+        let sync: Value = serde_json::from_str(body)?;
+        let tx = self.conn.transaction()?;
+
+        // anything that doesn't carry on from what's stored would leave a gap, like the syncs after
+        // one that failed to save, so stay put and let the next run catch up from there instead
+        let stored = tx
+            .query_row("SELECT next_batch FROM sync", NO_PARAMS, |row| row.get::<_, String>(0))
+            .optional()?;
+        if stored.as_deref() != since {
+            return Ok(());
+        }
+
+        let rooms = &sync["rooms"];
+        for (room_id, room) in rooms["join"].as_object().into_iter().flatten() {
+            save_joined(&tx, room_id, room)?;
+        }
+
+        for (room_id, room) in rooms["invite"].as_object().into_iter().flatten() {
+            tx.execute(
+                "INSERT OR REPLACE INTO invites (room_id, room) VALUES (?1, ?2)",
+                params![room_id, room.to_string()],
+            )?;
+        }
+
+        for room_id in rooms["leave"].as_object().into_iter().flat_map(Map::keys) {
+            forget(&tx, room_id)?;
+        }
+
+        save_account_data(&tx, "", &sync["account_data"])?;
+        tx.execute(
+            "INSERT OR REPLACE INTO sync (id, next_batch) VALUES (0, ?1)",
+            params![sync["next_batch"].as_str().unwrap_or_default()],
+        )?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    // rebuilds a sync response out of everything stored, so it goes down the same path as a real one
+    pub fn load(&self) -> Result<Option<SyncState>, Error> {
+        let next_batch = self
+            .conn
+            .query_row("SELECT next_batch FROM sync", NO_PARAMS, |row| row.get::<_, String>(0))
+            .optional()?;
+        let next_batch = match next_batch {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        let mut join = Map::new();
+        let mut statement = self
+            .conn
+            .prepare("SELECT room_id, prev_batch, summary, unread_notifications FROM rooms")?;
+        let rows = statement.query_map(NO_PARAMS, |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+        for row in rows {
+            let (room_id, prev_batch, summary, unread_notifications) = row?;
+            let room = json!({
+                "summary": serde_json::from_str::<Value>(&summary)?,
+                "state": {
+                    "events": self.events("SELECT event FROM room_state WHERE room_id = ?1", &room_id)?,
+                },
+                "timeline": {
+                    "events": self.events("SELECT event FROM timeline WHERE room_id = ?1 ORDER BY seq", &room_id)?,
+                    "limited": true,
+                    "prev_batch": prev_batch,
+                },
+                "ephemeral": { "events": [] },
+                "account_data": {
+                    "events": self.events("SELECT event FROM account_data WHERE room_id = ?1", &room_id)?,
+                },
+                "unread_notifications": serde_json::from_str::<Value>(&unread_notifications)?,
+            });
+            join.insert(room_id, room);
+        }
+
+        let mut invite = Map::new();
+        let mut statement = self.conn.prepare("SELECT room_id, room FROM invites")?;
+        let rows = statement.query_map(NO_PARAMS, |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (room_id, room) = row?;
+            invite.insert(room_id, serde_json::from_str(&room)?);
+        }
+
+        let sync = json!({
+            "next_batch": next_batch,
+            "rooms": {
+                "join": join,
+                "invite": invite,
+            },
+            "account_data": {
+                "events": self.events("SELECT event FROM account_data WHERE room_id = ?1", "")?,
+            },
+        });
+        Ok(Some(chat::parse_sync(&sync.to_string())?))
+    }
+
+    fn events(&self, query: &str, room_id: &str) -> Result<Vec<Value>, Error> {
+        let mut statement = self.conn.prepare(query)?;
+        let rows = statement.query_map(params![room_id], |row| row.get::<_, String>(0))?;
+        rows.map(|v| Ok(serde_json::from_str(&v?)?)).collect()
+    }
+}
+
+fn save_joined(tx: &Transaction, room_id: &str, room: &Value) -> Result<(), Error> {
+    tx.execute("DELETE FROM invites WHERE room_id = ?1", params![room_id])?;
+
+    // the summary only carries what changed since the last sync
+    let mut summary = tx
+        .query_row("SELECT summary FROM rooms WHERE room_id = ?1", params![room_id], |row| {
+            row.get::<_, String>(0)
+        })
+        .optional()?
+        .map(|v| serde_json::from_str::<Map<String, Value>>(&v))
+        .transpose()?
+        .unwrap_or_default();
+    if let Some(changes) = room["summary"].as_object() {
+        summary.extend(changes.clone());
+    }
+
+    let timeline = &room["timeline"];
+    let prev_batch = timeline["prev_batch"].as_str().unwrap_or_default();
+    if timeline["limited"].as_bool().unwrap_or(false) {
+        // there's a gap between what we have and what's new, so start over from here
+        tx.execute("DELETE FROM timeline WHERE room_id = ?1", params![room_id])?;
+    }
+
+    let events = timeline["events"].as_array().into_iter().flatten();
+    let state = room["state"]["events"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .chain(events.clone().filter(|v| v.get("state_key").is_some()));
+    for event in state {
+        tx.execute(
+            "INSERT OR REPLACE INTO room_state (room_id, event_type, state_key, event) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                room_id,
+                event["type"].as_str().unwrap_or_default(),
+                event["state_key"].as_str().unwrap_or_default(),
+                event.to_string(),
+            ],
+        )?;
+    }
+
+    for event in events.filter(|v| v.get("state_key").is_none()) {
+        tx.execute(
+            "INSERT INTO timeline (room_id, prev_batch, event) VALUES (?1, ?2, ?3)",
+            params![room_id, prev_batch, event.to_string()],
+        )?;
+    }
+
+    // only whole batches go, so the oldest one's prev_batch still points right before what we kept
+    tx.execute(
+        "DELETE FROM timeline WHERE room_id = ?1 AND seq < (
+            SELECT MIN(seq) FROM timeline WHERE room_id = ?1 AND prev_batch = (
+                SELECT prev_batch FROM timeline WHERE room_id = ?1 ORDER BY seq DESC LIMIT 1 OFFSET ?2
+            )
+        )",
+        params![room_id, TIMELINE_LIMIT - 1],
+    )?;
+
+    let oldest = tx
+        .query_row(
+            "SELECT prev_batch FROM timeline WHERE room_id = ?1 ORDER BY seq LIMIT 1",
+            params![room_id],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    tx.execute(
+        "INSERT OR REPLACE INTO rooms (room_id, prev_batch, summary, unread_notifications) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            room_id,
+            oldest.as_deref().unwrap_or(prev_batch),
+            Value::Object(summary).to_string(),
+            room["unread_notifications"].to_string(),
+        ],
+    )?;
+
+    save_account_data(tx, room_id, &room["account_data"])
+}
+
+fn save_account_data(tx: &Transaction, room_id: &str, account_data: &Value) -> Result<(), Error> {
+    for event in account_data["events"].as_array().into_iter().flatten() {
+        tx.execute(
+            "INSERT OR REPLACE INTO account_data (room_id, event_type, event) VALUES (?1, ?2, ?3)",
+            params![room_id, event["type"].as_str().unwrap_or_default(), event.to_string()],
+        )?;
+    }
+    Ok(())
+}
+
+fn forget(tx: &Transaction, room_id: &str) -> Result<(), Error> {
+    for table in &["rooms", "room_state", "timeline", "account_data", "invites"] {
+        tx.execute(&format!("DELETE FROM {} WHERE room_id = ?1", table), params![room_id])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn message(id: &str) -> Value {
+        json!({
+            "type": "m.room.message",
+            "event_id": id,
+            "sender": "@alice:example.org",
+            "origin_server_ts": 0,
+            "unsigned": {},
+            "content": { "msgtype": "m.text", "body": id },
+        })
+    }
+
+    fn sync(next_batch: &str, prev_batch: &str, limited: bool, events: Vec<Value>) -> String {
+        json!({
+            "next_batch": next_batch,
+            "rooms": {
+                "join": {
+                    "!room:example.org": {
+                        "summary": { "m.joined_member_count": 2 },
+                        "state": {
+                            "events": [{
+                                "type": "m.room.name",
+                                "state_key": "",
+                                "event_id": "$name",
+                                "sender": "@alice:example.org",
+                                "origin_server_ts": 0,
+                                "unsigned": {},
+                                "content": { "name": "uwutalk fans" },
+                            }],
+                        },
+                        "timeline": { "events": events, "limited": limited, "prev_batch": prev_batch },
+                        "ephemeral": { "events": [] },
+                        "account_data": { "events": [{ "type": "m.tag", "content": { "tags": { "u.work": { "order": 0.5 } } } }] },
+                        "unread_notifications": { "highlight_count": 1, "notification_count": 2 },
+                    },
+                },
+                "invite": { "!invite:example.org": { "invite_state": { "events": [] } } },
+            },
+            "account_data": { "events": [{ "type": "m.direct", "content": { "@bob:example.org": ["!room:example.org"] } }] },
+        })
+        .to_string()
+    }
+
+    fn batch(prefix: &str, count: usize) -> Vec<Value> {
+        (0..count).map(|i| message(&format!("${}{}", prefix, i))).collect()
+    }
+
+    fn timeline(store: &Store) -> (String, Vec<String>) {
+        let sync = store.load().unwrap().unwrap();
+        let rooms = sync.rooms.unwrap();
+        let room = &rooms.join.unwrap()[&Arc::new(String::from("!room:example.org"))];
+        let events = room.timeline.events.iter().map(|v| (*v.event_id).clone()).collect();
+        (room.timeline.prev_batch.clone(), events)
+    }
+
+    #[test]
+    fn load_round_trip() {
+        let mut store = Store::open(":memory:").unwrap();
+        assert!(store.load().unwrap().is_none());
+
+        store.save_sync(None, &sync("n1", "p1", false, batch("a", 3))).unwrap();
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.next_batch.as_str(), "n1");
+
+        let rooms = loaded.rooms.unwrap();
+        assert_eq!(rooms.invite.unwrap().len(), 1);
+        let room = &rooms.join.unwrap()[&Arc::new(String::from("!room:example.org"))];
+        assert_eq!(room.summary.joined_member_count, Some(2));
+        assert_eq!(room.state.events.len(), 1);
+        assert_eq!(room.account_data.events.len(), 1);
+        assert_eq!(room.unread_notifications.notification_count, 2);
+        assert_eq!(loaded.account_data.unwrap().events.len(), 1);
+        assert_eq!(timeline(&store), (String::from("p1"), vec![String::from("$a0"), String::from("$a1"), String::from("$a2")]));
+
+        store.clear().unwrap();
+        assert!(store.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn limited_timeline_starts_over() {
+        let mut store = Store::open(":memory:").unwrap();
+        store.save_sync(None, &sync("n1", "p1", false, batch("a", 3))).unwrap();
+        store.save_sync(Some("n1"), &sync("n2", "p2", true, batch("b", 1))).unwrap();
+        assert_eq!(timeline(&store), (String::from("p2"), vec![String::from("$b0")]));
+    }
+
+    #[test]
+    fn trims_whole_batches() {
+        let mut store = Store::open(":memory:").unwrap();
+        store.save_sync(None, &sync("n1", "p1", false, batch("a", 30))).unwrap();
+        store.save_sync(Some("n1"), &sync("n2", "p2", false, batch("b", 30))).unwrap();
+        let (prev_batch, events) = timeline(&store);
+        assert_eq!((prev_batch.as_str(), events.len()), ("p1", 60));
+
+        // the limit falls inside the second batch, so all of the first one goes
+        store.save_sync(Some("n2"), &sync("n3", "p3", false, batch("c", 30))).unwrap();
+        let (prev_batch, events) = timeline(&store);
+        assert_eq!((prev_batch.as_str(), events.len()), ("p2", 60));
+        assert_eq!(events[0], "$b0");
+    }
+
+    #[test]
+    fn skips_syncs_that_leave_a_gap() {
+        let mut store = Store::open(":memory:").unwrap();
+        store.save_sync(None, &sync("n1", "p1", false, batch("a", 1))).unwrap();
+        store.save_sync(Some("n2"), &sync("n3", "p3", false, batch("c", 1))).unwrap();
+        assert_eq!(store.load().unwrap().unwrap().next_batch.as_str(), "n1");
+        assert_eq!(timeline(&store).1, vec![String::from("$a0")]);
+    }
+}
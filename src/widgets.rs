@@ -1,11 +1,163 @@
-/*
+use druid::piet::{InterpolationMode, PietImage};
 use druid::widget::prelude::*;
-use druid::widget::WidgetExt;
-use druid::WindowSizePolicy;
-use druid::{
-    commands::CLOSE_WINDOW,
-    widget::{Scroll, SizedBox},
-};
-use druid::{Point, Selector, WindowConfig};
-use druid::{WindowId, WindowLevel};
-*/
+use druid::{ImageBuf, Point, Rect, Selector, Vec2};
+
+pub const ZOOM_IN: Selector = Selector::new("uwutalk.image_viewer.zoom_in");
+pub const ZOOM_OUT: Selector = Selector::new("uwutalk.image_viewer.zoom_out");
+pub const FIT: Selector = Selector::new("uwutalk.image_viewer.fit");
+pub const ACTUAL_SIZE: Selector = Selector::new("uwutalk.image_viewer.actual_size");
+
+const ZOOM_STEP: f64 = 1.25;
+const MIN_ZOOM: f64 = 0.05;
+const MAX_ZOOM: f64 = 32.0;
+
+pub struct ImageViewer {
+    cache: Option<PietImage>,
+    fit: bool,
+    zoom: f64,
+    offset: Vec2,
+    drag: Option<Point>,
+}
+
+impl Default for ImageViewer {
+    fn default() -> ImageViewer {
+        ImageViewer::new()
+    }
+}
+
+impl ImageViewer {
+    pub fn new() -> ImageViewer {
+        ImageViewer {
+            cache: None,
+            fit: true,
+            zoom: 1.0,
+            offset: Vec2::ZERO,
+            drag: None,
+        }
+    }
+
+    fn scale(&self, size: Size, image: Size) -> f64 {
+        if !self.fit {
+            self.zoom
+        } else if image.width == 0.0 || image.height == 0.0 {
+            1.0
+        } else {
+            (size.width / image.width).min(size.height / image.height)
+        }
+    }
+
+    fn centered(size: Size, image: Size, scale: f64) -> Point {
+        Point::new(
+            (size.width - image.width * scale) / 2.0,
+            (size.height - image.height * scale) / 2.0,
+        )
+    }
+
+    fn origin(&self, size: Size, image: Size) -> Point {
+        ImageViewer::centered(size, image, self.scale(size, image)) + self.offset
+    }
+
+    // keeps whatever is under `point` in the same place on screen
+    fn zoom_at(&mut self, size: Size, image: Size, point: Point, factor: f64) {
+        let scale = self.scale(size, image);
+        let origin = self.origin(size, image);
+        let zoom = (scale * factor).max(MIN_ZOOM).min(MAX_ZOOM);
+        let under = (point - origin) / scale;
+
+        self.fit = false;
+        self.zoom = zoom;
+        self.offset = (point - under * zoom) - ImageViewer::centered(size, image, zoom);
+    }
+}
+
+impl Widget<ImageBuf> for ImageViewer {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut ImageBuf, _env: &Env) {
+        match event {
+            Event::Wheel(m) => {
+                let factor = if m.wheel_delta.y < 0.0 { ZOOM_STEP } else { 1.0 / ZOOM_STEP };
+                self.zoom_at(ctx.size(), data.size(), m.pos, factor);
+                ctx.request_paint();
+                ctx.set_handled();
+            }
+
+            Event::MouseDown(m) if m.button.is_left() => {
+                self.drag = Some(m.pos);
+                ctx.set_active(true);
+            }
+
+            Event::MouseMove(m) if ctx.is_active() => {
+                if let Some(last) = self.drag {
+                    if self.fit {
+                        self.zoom = self.scale(ctx.size(), data.size());
+                        self.fit = false;
+                    }
+
+                    self.offset += m.pos - last;
+                    self.drag = Some(m.pos);
+                    ctx.request_paint();
+                }
+            }
+
+            Event::MouseUp(m) if m.button.is_left() => {
+                self.drag = None;
+                ctx.set_active(false);
+            }
+
+            Event::Command(cmd) if cmd.is(ZOOM_IN) || cmd.is(ZOOM_OUT) => {
+                let size = ctx.size();
+                let factor = if cmd.is(ZOOM_IN) { ZOOM_STEP } else { 1.0 / ZOOM_STEP };
+                self.zoom_at(size, data.size(), (size.to_vec2() / 2.0).to_point(), factor);
+                ctx.request_paint();
+            }
+
+            Event::Command(cmd) if cmd.is(FIT) => {
+                self.fit = true;
+                self.offset = Vec2::ZERO;
+                ctx.request_paint();
+            }
+
+            Event::Command(cmd) if cmd.is(ACTUAL_SIZE) => {
+                self.fit = false;
+                self.zoom = 1.0;
+                self.offset = Vec2::ZERO;
+                ctx.request_paint();
+            }
+
+            _ => (),
+        }
+    }
+
+    fn lifecycle(&mut self, _ctx: &mut LifeCycleCtx, _event: &LifeCycle, _data: &ImageBuf, _env: &Env) {}
+
+    fn update(&mut self, ctx: &mut UpdateCtx, old_data: &ImageBuf, data: &ImageBuf, _env: &Env) {
+        if !old_data.same(data) {
+            self.cache = None;
+            self.fit = true;
+            self.offset = Vec2::ZERO;
+            ctx.request_paint();
+        }
+    }
+
+    fn layout(&mut self, _ctx: &mut LayoutCtx, bc: &BoxConstraints, data: &ImageBuf, _env: &Env) -> Size {
+        if bc.is_width_bounded() && bc.is_height_bounded() {
+            bc.max()
+        } else {
+            bc.constrain(data.size())
+        }
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, data: &ImageBuf, _env: &Env) {
+        let size = ctx.size();
+        let image = data.size();
+        let rect = Rect::from_origin_size(self.origin(size, image), image * self.scale(size, image));
+
+        if self.cache.is_none() {
+            self.cache = Some(data.to_image(ctx.render_ctx));
+        }
+
+        ctx.clip(size.to_rect());
+        if let Some(cache) = &self.cache {
+            ctx.draw_image(cache, rect, InterpolationMode::Bilinear);
+        }
+    }
+}